@@ -1,3 +1,24 @@
 fn main() {
-    prost_build::compile_protos(&["proto/syntaxdot.proto"], &["proto/"]).unwrap();
+    let mut config = prost_build::Config::new();
+
+    // Sentences can also be exchanged as JSON, using the protobuf field names.
+    config.type_attribute(
+        ".syntaxdot.sentence",
+        "#[derive(serde::Deserialize, serde::Serialize)] #[serde(default)]",
+    );
+
+    config
+        .compile_protos(
+            &[
+                "proto/syntaxdot.proto",
+                "proto/model.proto",
+                "proto/options.proto",
+                "proto/segmenter.proto",
+                "proto/embeddings.proto",
+                "proto/pieces.proto",
+                "proto/sentencepiece_model.proto",
+            ],
+            &["proto/"],
+        )
+        .unwrap();
 }
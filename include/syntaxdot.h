@@ -1,6 +1,7 @@
 #ifndef SYNTAXDOT_H
 #define SYNTAXDOT_H
 
+#include <stdbool.h>
 #include <stddef.h>
 #include <stdint.h>
 
@@ -40,16 +41,93 @@ typedef struct {
 } ByteBuffer;
 
 /**
+ * <p>
  * Load a syntaxdot annotation model.
- *
+ * </p>
+ * <p>
  * This function, when successful, returns a handle for the loaded model.
+ * </p>
+ * <p>
+ * The path can also refer to a packaged model archive: a ZIP file with a
+ * <tt>manifest.toml</tt> that specifies the configuration file and the
+ * SHA-256 checksums of the archive members. Missing and corrupt members
+ * are reported with dedicated error codes. The parameters are extracted
+ * to an anonymous in-memory file, so archives can only be loaded on
+ * Linux. On other platforms, loading an archive fails with an
+ * unsupported error.
+ * </p>
  *
- * @param path The path to the model configuration
+ * @param path The path to the model configuration or model archive
  * @param err Pointer to an error value.
  * @return The handle for the annotator.
  */
 uint64_t syntaxdot_annotator_load(char const *config_path, ExternError *err);
 
+/**
+ * <p>
+ * Load a syntaxdot annotation model with options.
+ * </p>
+ * <p>
+ * The options must be provided as a serialized
+ * <tt>syntaxdot.options.AnnotatorOptions</tt> protobuf message. Invalid
+ * options result in a dedicated error code, such as an invalid device or
+ * an unknown encoder.
+ * </p>
+ * <p>
+ * Torch thread pools are shared by all annotators. Thread options are
+ * therefore rejected with an invalid option error when another annotator
+ * is loaded. They are only applied once the annotator is loaded
+ * successfully, a failed load does not change the thread pools. Loading
+ * annotators is serialized, so that concurrent loads cannot both apply
+ * their thread options. Use <tt>syntaxdot_set_num_interop_threads</tt> and
+ * <tt>syntaxdot_set_num_intraop_threads</tt> to change the thread pools
+ * of all annotators.
+ * </p>
+ *
+ * @param path The path to the model configuration
+ * @param options_data Pointer to the protocol buffer data.
+ * @param options_data_len Length of the protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return The handle for the annotator.
+ */
+uint64_t syntaxdot_annotator_load_with_options(char const *config_path,
+                                               uint8_t const *options_data,
+                                               int32_t options_data_len,
+                                               ExternError *err);
+
+/**
+ * <p>
+ * Load a syntaxdot annotation model from memory.
+ * </p>
+ * <p>
+ * The model must be provided as a serialized
+ * <tt>syntaxdot.model.ModelData</tt> protobuf message, which holds the
+ * configuration, label files, tokenizer vocabulary, pretrained model
+ * configuration, and parameters. The options must be provided as a
+ * serialized <tt>syntaxdot.options.AnnotatorOptions</tt> message, or
+ * an empty buffer to use the default options. Thread options are
+ * handled as in <tt>syntaxdot_annotator_load_with_options</tt>.
+ * </p>
+ * <p>
+ * The parameters are never written to disk. Since Torch can only read
+ * parameters from a path, they are loaded through an anonymous
+ * in-memory file. This is only supported on Linux, on other platforms
+ * this function fails with an unsupported error.
+ * </p>
+ *
+ * @param model_data Pointer to the model protocol buffer data.
+ * @param model_data_len Length of the model protocol buffer data.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return The handle for the annotator.
+ */
+uint64_t syntaxdot_annotator_load_from_data(uint8_t const *model_data,
+                                            int32_t model_data_len,
+                                            uint8_t const *options_data,
+                                            int32_t options_data_len,
+                                            ExternError *err);
+
 /**
  * Free a syntaxdot annotation model.
  *
@@ -68,11 +146,29 @@ void syntaxdot_annotator_free(uint64_t handle, ExternError *err);
  * <tt>sentences_data</tt> must be a pointer to protobuf data with the length
  * <tt>sentences_data_length<tt>.
  * </p>
+ * <p>
+ * The character offsets (<tt>start</tt>, <tt>end</tt>) and
+ * <tt>no_space_after</tt> fields of tokens, and the <tt>id</tt>,
+ * <tt>id_position</tt>, <tt>metadata</tt>, <tt>multiword_tokens</tt>, and
+ * <tt>empty_nodes</tt> fields of sentences are returned unchanged. Sentences are returned in
+ * the order in which they were provided.
+ * </p>
+ * <p>
+ * The <tt>allowed_labels</tt> field of a token constrains the labels
+ * that sequence labeling layers can assign to the token. The most
+ * probable allowed label is chosen. An error is returned when a layer is
+ * not a sequence labeling layer or a label is not known to the model.
+ * </p>
+ * <p>
+ * The head of a token with a relation must be 0 (the root) or one of
+ * the tokens of its sentence, otherwise an invalid constraint error is
+ * returned.
+ * </p>
  *
  * @param handle The handle of the model to annotate with.
  * @param sentences_data Pointer to the protocol buffer data.
  * @param sentences_data_len Length of the protocol buffer data.
- * @param batch_size Model batch size.
+ * @param batch_size Model batch size, 0 to use the default batch size.
  * @param err Pointer to an error value.
  * @return Buffer with the annotations serialized to protobuf.
  */
@@ -81,12 +177,430 @@ ByteBuffer syntaxdot_annotator_annotate(uint64_t handle, uint8_t *sentences_data
                                         ExternError *err);
 
 /**
- * Set the number of Torch inter-op threads.
+ * <p>
+ * Annotate sentences using a model with options.
+ * </p>
+ * <p>
+ * This function is like <tt>syntaxdot_annotator_annotate</tt>, but takes
+ * the options of the annotate call as a serialized
+ * <tt>syntaxdot.options.AnnotateOptions</tt> protobuf message, or an empty
+ * buffer to use the default options.
+ * </p>
+ * <p>
+ * When <tt>confidences</tt> is set, the <tt>confidences</tt> field of
+ * each token contains the probability of the label that was chosen for
+ * each layer. Sequence labeling layers use the encoder name as the key,
+ * the biaffine parser uses <tt>head</tt> and <tt>relation</tt>.
+ * </p>
+ * <p>
+ * <tt>top_k</tt> maps layer names to the number of most probable labels
+ * that should be returned in the <tt>top_k</tt> field of each token. An
+ * error is returned when the model does not assign one of the layers.
+ * </p>
+ * <p>
+ * <tt>head_scores</tt> requests the head score logits or head
+ * probabilities of the biaffine parser. They are stored in the
+ * <tt>head_scores</tt> field of each sentence as a matrix with a row
+ * per token and a column per candidate head, including the root.
+ * </p>
+ * <p>
+ * <tt>policies</tt> maps layer names to annotation policies. By default,
+ * layers are overwritten. With <tt>KEEP_IF_PRESENT</tt>, values that the
+ * input sentences already have are kept and only missing values are
+ * predicted. With <tt>SKIP</tt>, the layer is not annotated at all. All
+ * other token fields are preserved. When the head and relation are kept,
+ * the biaffine parser attaches the remaining tokens such that the result
+ * is a tree, which fails when the kept relations form a cycle.
+ * </p>
+ * <p>
+ * <tt>layers</tt> restricts annotation to the given layers. The other
+ * layers are skipped and the biaffine parser is not run when
+ * <tt>head</tt> and <tt>relation</tt> are not selected, unless head
+ * scores are requested. Since the parser assigns both, selecting only
+ * one of them is an invalid option. Layers can also be disabled for all calls when the
+ * annotator is loaded, see <tt>syntaxdot_annotator_load_with_options</tt>.
+ * </p>
+ * <p>
+ * <tt>long_sentences</tt> determines how sentences with more word pieces
+ * than the maximum sequence length are handled. By default, the call
+ * fails. With <tt>TRUNCATE_LONG_SENTENCES</tt>, only the leading tokens
+ * that fit are annotated and the other tokens have their
+ * <tt>truncated</tt> field set. With <tt>SLIDING_WINDOW</tt>, the
+ * sentence is encoded in windows that share <tt>window_overlap</tt>
+ * word pieces and all tokens are annotated.
+ * </p>
+ * <p>
+ * With <tt>per_sentence_errors</tt>, a sentence that cannot be annotated
+ * does not fail the call. It is returned unchanged with its
+ * <tt>status</tt> field set to the error code and message, while the
+ * other sentences are annotated. Invalid options still fail the call.
+ * </p>
+ * <p>
+ * <tt>max_batch_pieces</tt> limits the number of word pieces in a batch,
+ * counting the padding of sentences to the longest sentence in the
+ * batch. This bounds the memory use of batches with long sentences.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return Buffer with the annotations serialized to protobuf.
  */
-void syntaxdot_set_num_intraop_threads(int32_t n_threads);
+ByteBuffer syntaxdot_annotator_annotate_with_options(uint64_t handle,
+                                                     uint8_t const *sentences_data,
+                                                     int32_t sentences_data_len,
+                                                     uint8_t const *options_data,
+                                                     int32_t options_data_len,
+                                                     ExternError *err);
+
+/**
+ * <p>
+ * Annotate CoNLL-U sentences using a model.
+ * </p>
+ * <p>
+ * This function annotates the sentences in <tt>conllu</tt>, which must be
+ * NUL-terminated CoNLL-U text, using the model specified by
+ * <tt>handle</tt>. The annotated sentences are returned as CoNLL-U text.
+ * Multiword tokens and empty nodes are returned unchanged.
+ * The caller is responsible for deallocating the returned string with
+ * <tt>syntaxdot_free_string</tt>.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param conllu The sentences in CoNLL-U format.
+ * @param batch_size Model batch size, 0 to use the default batch size.
+ * @param err Pointer to an error value.
+ * @return The annotated sentences in CoNLL-U format.
+ */
+char *syntaxdot_annotator_annotate_conllu(uint64_t handle, char const *conllu,
+                                          size_t batch_size, ExternError *err);
+
+/**
+ * <p>
+ * Annotate JSON sentences using a model.
+ * </p>
+ * <p>
+ * This function annotates the sentences in <tt>json</tt>, which must be
+ * NUL-terminated JSON, using the model specified by <tt>handle</tt>. The
+ * JSON encoding uses the fields of the <tt>syntaxdot.sentence.Sentences</tt>
+ * protobuf message, with the same semantics: an empty or absent string is
+ * an absent value and a token only has a head when it has a relation. The
+ * annotated sentences are returned in the same encoding. The caller is
+ * responsible for deallocating the returned string with
+ * <tt>syntaxdot_free_string</tt>.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param json The sentences in JSON format.
+ * @param batch_size Model batch size, 0 to use the default batch size.
+ * @param err Pointer to an error value.
+ * @return The annotated sentences in JSON format.
+ */
+char *syntaxdot_annotator_annotate_json(uint64_t handle, char const *json,
+                                        size_t batch_size, ExternError *err);
+
+/**
+ * <p>
+ * Annotate raw text using a model.
+ * </p>
+ * <p>
+ * This function splits <tt>text</tt>, which must be NUL-terminated UTF-8,
+ * into sentences and tokens using a rule-based segmenter, and annotates
+ * the sentences using the model specified by <tt>handle</tt>. The segmenter
+ * options must be provided as a serialized
+ * <tt>syntaxdot.segmenter.SegmenterOptions</tt> protobuf message, or an
+ * empty buffer to use the default options.
+ * </p>
+ * <p>
+ * The character offsets of each token are stored in its <tt>start</tt>
+ * and <tt>end</tt> fields, where <tt>end</tt> is exclusive. Tokens that
+ * are not followed by whitespace have <tt>no_space_after</tt> set.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param text The text to annotate.
+ * @param options_data Pointer to the segmenter options protocol buffer data.
+ * @param options_data_len Length of the segmenter options protocol buffer data.
+ * @param batch_size Model batch size, 0 to use the default batch size.
+ * @param err Pointer to an error value.
+ * @return Buffer with the annotations serialized to protobuf.
+ */
+ByteBuffer syntaxdot_annotator_annotate_text(uint64_t handle, char const *text,
+                                             uint8_t const *options_data,
+                                             int32_t options_data_len,
+                                             size_t batch_size, ExternError *err);
+
+/**
+ * <p>
+ * Compute token embeddings using a model.
+ * </p>
+ * <p>
+ * The sentences must be provided as a serialized
+ * <tt>syntaxdot.sentence.Sentences</tt> protobuf message. The embed
+ * options must be a serialized <tt>syntaxdot.options.EmbedOptions</tt>
+ * protobuf message, or an empty buffer to use the default options. By
+ * default, the embeddings are the outputs of the last layer.
+ * </p>
+ * <p>
+ * The embeddings are returned as a serialized
+ * <tt>syntaxdot.embeddings.Embeddings</tt> protobuf message, which
+ * contains a matrix with a row per token and the sentence and token index
+ * of every row. Word pieces are pooled into tokens using the pooler of
+ * the model.
+ * </p>
+ *
+ * @param handle The handle of the model to embed with.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return Buffer with the embeddings serialized to protobuf.
+ */
+ByteBuffer syntaxdot_annotator_embed(uint64_t handle,
+                                     uint8_t const *sentences_data,
+                                     int32_t sentences_data_len,
+                                     uint8_t const *options_data,
+                                     int32_t options_data_len,
+                                     ExternError *err);
+
+/**
+ * <p>
+ * Compute sentence embeddings using a model.
+ * </p>
+ * <p>
+ * This function is like <tt>syntaxdot_annotator_embed</tt>, but pools
+ * the token embeddings of each sentence into a single vector, using the
+ * <tt>pooling</tt> method of the options. The embeddings are returned
+ * as a serialized <tt>syntaxdot.embeddings.SentenceEmbeddings</tt>
+ * protobuf message, which contains a matrix with a row per sentence and
+ * its shape.
+ * </p>
+ *
+ * @param handle The handle of the model to embed with.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return Buffer with the embeddings serialized to protobuf.
+ */
+ByteBuffer syntaxdot_annotator_embed_sentences(uint64_t handle,
+                                               uint8_t const *sentences_data,
+                                               int32_t sentences_data_len,
+                                               uint8_t const *options_data,
+                                               int32_t options_data_len,
+                                               ExternError *err);
+
+/**
+ * <p>
+ * Split sentences into word pieces.
+ * </p>
+ * <p>
+ * The sentences are split into the pieces of the model's vocabulary,
+ * without running the model. The result is a serialized
+ * <tt>syntaxdot.pieces.TokenizedSentences</tt> protobuf message, which
+ * contains the piece identifiers, the piece strings, and the offset of
+ * the first piece of every token for each sentence. The sentences are
+ * not checked against the maximum sequence length of the annotator.
+ * </p>
+ *
+ * @param handle The handle of the model to tokenize with.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return Buffer with the word pieces serialized to protobuf.
+ */
+ByteBuffer syntaxdot_annotator_tokenize(uint64_t handle,
+                                        uint8_t const *sentences_data,
+                                        int32_t sentences_data_len,
+                                        ExternError *err);
+
+/**
+ * <p>
+ * Open a streaming annotation session.
+ * </p>
+ * <p>
+ * A session annotates sentences that are pushed with
+ * <tt>syntaxdot_session_push</tt> in batches that can span several
+ * pushes. Annotated sentences are retrieved incrementally with
+ * <tt>syntaxdot_session_poll</tt>, so that a large corpus does not have
+ * to be passed in a single buffer. Sentences are only kept until they
+ * are polled. A session buffers at most 4096 sentences, or the batch
+ * size if it is larger, counting both pending sentences and annotated
+ * sentences that were not polled.
+ * </p>
+ * <p>
+ * Sessions always report errors per sentence, as if
+ * <tt>per_sentence_errors</tt> is set in the options: a sentence that
+ * cannot be annotated is returned unchanged with its <tt>status</tt>
+ * field set.
+ * </p>
+ * <p>
+ * The options must be provided as a serialized
+ * <tt>syntaxdot.options.AnnotateOptions</tt> protobuf message, or an
+ * empty buffer to use the default options. They apply to all sentences
+ * of the session. The session must be closed with
+ * <tt>syntaxdot_session_close</tt> before the model is freed.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param err Pointer to an error value.
+ * @return The handle for the session.
+ */
+uint64_t syntaxdot_session_open(uint64_t handle, uint8_t const *options_data,
+                                int32_t options_data_len, ExternError *err);
+
+/**
+ * <p>
+ * Push sentences to a streaming annotation session.
+ * </p>
+ * <p>
+ * The sentences must be provided as serialized protobuf. Pending
+ * sentences are annotated as soon as they fill a batch, which is either
+ * the batch size or the largest batch that stays within
+ * <tt>max_batch_pieces</tt>.
+ * </p>
+ * <p>
+ * When the sentences do not fit in the session, a dedicated error code
+ * (18) is returned and none of the sentences are added.
+ * Poll the session and push the sentences again, or push fewer
+ * sentences at once. When the head of a token is not the root or a
+ * token of its sentence, an invalid constraint error (16) is returned
+ * and none of the sentences are added. When annotation fails, an error
+ * is returned and the sentences stay pending, so that they are
+ * annotated by a later push or poll.
+ * </p>
+ *
+ * @param handle The handle of the session.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param err Pointer to an error value.
+ */
+void syntaxdot_session_push(uint64_t handle, uint8_t const *sentences_data,
+                            int32_t sentences_data_len, ExternError *err);
+
+/**
+ * <p>
+ * Poll a streaming annotation session.
+ * </p>
+ * <p>
+ * Returns the sentences that were annotated since the previous poll,
+ * in the order in which they were pushed. When <tt>flush</tt> is set,
+ * pending sentences that do not fill a batch are annotated first, which
+ * should be done after the last push. Sentences that could not be
+ * annotated because of an error stay pending.
+ * </p>
+ *
+ * @param handle The handle of the session.
+ * @param flush Annotate pending sentences.
+ * @param err Pointer to an error value.
+ * @return Buffer with the annotations serialized to protobuf.
+ */
+ByteBuffer syntaxdot_session_poll(uint64_t handle, bool flush, ExternError *err);
+
+/**
+ * Close a streaming annotation session.
+ *
+ * Sentences that were not polled are discarded.
+ *
+ * @param handle The handle of the session to close.
+ * @param err Pointer to an error value.
+ */
+void syntaxdot_session_close(uint64_t handle, ExternError *err);
+
+/**
+ * <p>
+ * Callback that receives the result of an asynchronous call.
+ * </p>
+ * <p>
+ * The callback is called on a worker thread of the library. It takes
+ * ownership of <tt>result</tt> and the message of <tt>err</tt>, which
+ * must be deallocated with <tt>syntaxdot_free_bytebuffer</tt> and
+ * <tt>syntaxdot_free_string</tt>.
+ * </p>
+ *
+ * @param user_data The user data that was passed to the call.
+ * @param result Buffer with the result, empty when the call failed.
+ * @param err The error of the call.
+ */
+typedef void (*syntaxdot_callback)(void *user_data, ByteBuffer result,
+                                   ExternError err);
+
+/**
+ * <p>
+ * Annotate sentences asynchronously.
+ * </p>
+ * <p>
+ * This function is like <tt>syntaxdot_annotator_annotate_with_options</tt>,
+ * but returns once the call is queued. The sentences are annotated on a
+ * worker thread of the library, which calls <tt>callback</tt> with
+ * <tt>user_data</tt> and the annotated sentences or the error. The data
+ * buffers are copied and can be deallocated when this function returns.
+ * </p>
+ * <p>
+ * This function never blocks. At most 64 calls wait for a worker, when
+ * the queue is full the call fails with a dedicated error code (19).
+ * Wait for pending calls to finish before making more calls. When the
+ * worker threads have stopped, the call fails with error code 20.
+ * </p>
+ * <p>
+ * The callback is always called on a worker thread, except when the
+ * call cannot be queued. Only then <tt>callback</tt> is called with the
+ * error on the calling thread, before this function returns.
+ * </p>
+ * <p>
+ * Calls are started in the order in which they were made. The number
+ * of worker threads can be set with <tt>syntaxdot_set_num_workers</tt>.
+ * Calls that are pending when the model is freed fail with an invalid
+ * handle error.
+ * </p>
+ *
+ * @param handle The handle of the model to annotate with.
+ * @param sentences_data Pointer to the protocol buffer data.
+ * @param sentences_data_len Length of the protocol buffer data.
+ * @param options_data Pointer to the options protocol buffer data.
+ * @param options_data_len Length of the options protocol buffer data.
+ * @param callback The function that receives the result.
+ * @param user_data Pointer that is passed to the callback.
+ */
+void syntaxdot_annotator_annotate_async(uint64_t handle,
+                                        uint8_t const *sentences_data,
+                                        int32_t sentences_data_len,
+                                        uint8_t const *options_data,
+                                        int32_t options_data_len,
+                                        syntaxdot_callback callback,
+                                        void *user_data);
+
+/**
+ * Set the number of worker threads for asynchronous calls.
+ *
+ * The worker threads are started by the first asynchronous call, so
+ * this function must be called before that call. Defaults to 1. An
+ * invalid option error is returned when the workers were already
+ * started.
+ *
+ * @param n_workers The number of worker threads.
+ * @param err Pointer to an error value.
+ */
+void syntaxdot_set_num_workers(size_t n_workers, ExternError *err);
 
 /**
  * Set the number of Torch inter-op threads.
+ *
+ * This setting affects all loaded annotators.
+ */
+void syntaxdot_set_num_interop_threads(int32_t n_threads);
+
+/**
+ * Set the number of Torch intra-op threads.
+ *
+ * This setting affects all loaded annotators.
  */
 void syntaxdot_set_num_intraop_threads(int32_t n_threads);
 
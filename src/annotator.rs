@@ -1,19 +1,44 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::ops::Deref;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::mem;
+use std::ops::{Deref, Range};
 use std::path::Path;
+use std::slice;
 
-use syntaxdot::config::{BiaffineParserConfig, Config, PretrainConfig, TomlRead};
+use ndarray::{s, Array2};
+use sentencepiece::SentencePieceProcessor;
+use serde::Deserialize;
+use syntaxdot::config::{
+    BiaffineParserConfig, Config, PositionEmbeddings, PretrainConfig, PretrainModelType, Tokenizer,
+    TomlRead,
+};
 use syntaxdot::encoders::Encoders;
+use syntaxdot::error::SyntaxDotError;
 use syntaxdot::model::bert::BertModel;
-use syntaxdot::tagger::Tagger;
 use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
 use syntaxdot_tch_ext::RootExt;
-use syntaxdot_tokenizers::{SentenceWithPieces, Tokenize};
+use syntaxdot_tokenizers::{
+    AlbertTokenizer, BertTokenizer, SentenceWithPieces, Tokenize, TokenizerError,
+    XlmRobertaTokenizer,
+};
 use tch::nn::VarStore;
-use tch::Device;
-use udgraph::graph::Sentence;
+use udgraph::graph::{DepTriple, Sentence};
+use udgraph::token::Tokens;
 
+use crate::archive::ModelArchive;
+use crate::constraints::{dependency_policy, SentenceConstraints};
+use crate::embeddings::pool_sentences;
+use crate::labels::{read_encoder_labels, EncoderLabels, SerializedNumberer};
+use crate::memfile::MemFile;
+use crate::model::proto::ModelData;
+use crate::options::{
+    AnnotateOptions, AnnotationPolicy, AnnotatorOptions, EmbedOptions, HeadScores, LongSentences,
+};
+use crate::pieces::PieceVocab;
+use crate::tagger::{BiaffineDecoder, Decoders, SentenceScores, Tagger, HEAD, RELATION};
+use crate::windows::SlidingWindow;
 use crate::AnnotatorError;
 
 /// A wrapper of `Tagger` that is `Send + Sync`.
@@ -37,16 +62,49 @@ impl Deref for TaggerWrap {
     }
 }
 
+/// An annotated sentence.
+pub struct AnnotatedSentence {
+    pub sentence: Sentence,
+
+    /// The scores of the sentence, empty when no scores were requested.
+    pub scores: SentenceScores,
+
+    /// The error that prevented annotation of the sentence.
+    ///
+    /// Only set when per-sentence errors are requested, the sentence is
+    /// then returned unchanged.
+    pub error: Option<AnnotatorError>,
+}
+
 pub struct Annotator {
+    batch_size: usize,
+    end_piece: bool,
+    max_len: Option<usize>,
+    n_layers: usize,
+    piece_vocab: PieceVocab,
     tagger: TaggerWrap,
     tokenizer: Box<dyn Tokenize>,
 }
 
+/// The first position of XLM-RoBERTa, positions before it are reserved.
+const XLM_ROBERTA_POSITION_OFFSET: i64 = 2;
+
+/// Magic number of ZIP archives.
+const ZIP_MAGIC: &[u8; 4] = b"PK\x03\x04";
+
 impl Annotator {
-    pub fn load<P>(device: Device, config_path: P) -> Result<Self, AnnotatorError>
+    /// Load an annotator from a syntaxdot configuration file.
+    ///
+    /// If `config_path` is a model archive, the annotator is loaded
+    /// from the archive.
+    pub fn load<P>(config_path: P, options: &AnnotatorOptions) -> Result<Self, AnnotatorError>
     where
         P: AsRef<Path>,
     {
+        if is_archive(config_path.as_ref()) {
+            return Self::load_archive(config_path, options);
+        }
+
         let r = BufReader::new(File::open(&config_path).map_err(|err| {
             AnnotatorError::Io(
                 format!(
@@ -59,73 +117,822 @@ impl Annotator {
         let mut config = Config::from_toml_read(r)?;
         config.relativize_paths(config_path)?;
 
-        let biaffine_decoder = config
-            .biaffine
-            .as_ref()
+        let biaffine = biaffine_config(&config, options)
             .map(|config| load_biaffine_decoder(config))
             .transpose()?;
-        let encoders = load_encoders(&config)?;
+        let (encoders, labels) = load_encoders(&config, options.encoders.as_ref())?;
+        let decoders = Decoders {
+            biaffine,
+            encoders,
+            labels,
+        };
         let tokenizer = load_tokenizer(&config)?;
+        let piece_vocab = load_piece_vocab(&config)?;
         let pretrain_config = load_pretrain_config(&config)?;
 
-        let mut vs = VarStore::new(device);
+        Self::new(
+            &config,
+            decoders,
+            tokenizer,
+            piece_vocab,
+            &pretrain_config,
+            &config.model.parameters,
+            options,
+        )
+    }
+
+    /// Load an annotator from a model archive.
+    ///
+    /// The checksums of all archive members that are used are verified.
+    /// The parameters are loaded through an in-memory file, so archives
+    /// can only be loaded on Linux.
+    pub fn load_archive<P>(
+        archive_path: P,
+        options: &AnnotatorOptions,
+    ) -> Result<Self, AnnotatorError>
+    where
+        P: AsRef<Path>,
+    {
+        let mut archive = ModelArchive::open(archive_path)?;
+
+        let config_name = archive.config().to_owned();
+        let config_data = archive.read(&config_name)?;
+        let config = Config::from_toml_read(config_data.as_slice())?;
+
+        let biaffine_labels = match biaffine_config(&config, options) {
+            Some(biaffine) => archive.read_config_relative(&biaffine.labels)?,
+            None => Vec::new(),
+        };
+
+        // Stream the parameters into an in-memory file, so that they
+        // are not buffered twice.
+        let mut parameters = MemFile::new("parameters")?;
+        archive.copy_config_relative(&config.model.parameters, &mut parameters)?;
+
+        let data = ModelData {
+            biaffine_labels,
+            labels: archive.read_config_relative(&config.labeler.labels)?,
+            vocab: archive.read_config_relative(tokenizer_vocab(&config.input.tokenizer))?,
+            pretrain_config: archive.read_config_relative(&config.model.pretrain_config)?,
+            parameters: Vec::new(),
+            config: config_data,
+        };
+
+        Self::load_with_parameters(&data, parameters.path(), options)
+    }
+
+    /// Load an annotator from model data in memory.
+    ///
+    /// File names in the configuration are ignored, the data is read
+    /// from the corresponding fields of `data` instead. The parameters
+    /// are never written to disk. Since tch can only load parameters
+    /// from a path, they are loaded through an anonymous in-memory
+    /// file, which is only supported on Linux.
+    pub fn load_from_data(
+        data: &ModelData,
+        options: &AnnotatorOptions,
+    ) -> Result<Self, AnnotatorError> {
+        let mut parameters = MemFile::new("parameters")?;
+        parameters
+            .write_all(&data.parameters)
+            .and_then(|_| parameters.flush())
+            .map_err(|err| {
+                AnnotatorError::Io("Cannot write parameters to memory".to_string(), err)
+            })?;
+
+        Self::load_with_parameters(data, parameters.path(), options)
+    }
+
+    /// Load an annotator from model data and a parameter file.
+    ///
+    /// The `parameters` field of `data` is ignored.
+    fn load_with_parameters(
+        data: &ModelData,
+        parameters: &Path,
+        options: &AnnotatorOptions,
+    ) -> Result<Self, AnnotatorError> {
+        let config = Config::from_toml_read(data.config.as_slice())?;
+
+        let biaffine = biaffine_config(&config, options)
+            .map(|_| read_biaffine_decoder(data.biaffine_labels.as_slice(), "<biaffine labels>"))
+            .transpose()?;
+        let (encoders, labels) = read_encoders(
+            &config,
+            data.labels.as_slice(),
+            "<labels>",
+            options.encoders.as_ref(),
+        )?;
+        let decoders = Decoders {
+            biaffine,
+            encoders,
+            labels,
+        };
+        let tokenizer = read_tokenizer(&config, &data.vocab)?;
+        let piece_vocab = PieceVocab::read(&config.input.tokenizer, &data.vocab)?;
+        let pretrain_config = read_pretrain_config(&config, &data.pretrain_config)?;
+
+        Self::new(
+            &config,
+            decoders,
+            tokenizer,
+            piece_vocab,
+            &pretrain_config,
+            parameters,
+            options,
+        )
+    }
+
+    fn new(
+        config: &Config,
+        decoders: Decoders,
+        tokenizer: Box<dyn Tokenize>,
+        piece_vocab: PieceVocab,
+        pretrain_config: &PretrainConfig,
+        parameters: impl AsRef<Path>,
+        options: &AnnotatorOptions,
+    ) -> Result<Self, AnnotatorError> {
+        let mut vs = VarStore::new(options.device);
 
         let model = BertModel::new(
             vs.root_ext(|_| 0),
-            &pretrain_config,
-            config.biaffine.as_ref(),
-            biaffine_decoder
+            pretrain_config,
+            biaffine_config(config, options),
+            decoders
+                .biaffine
                 .as_ref()
-                .map(ImmutableDependencyEncoder::n_relations)
+                .map(|decoder| decoder.relations.len())
                 .unwrap_or(0),
-            &encoders,
+            &decoders.encoders,
             config.model.pooler,
             0.0,
             config.model.position_embeddings.clone(),
         )?;
 
-        vs.load(&config.model.parameters)?;
+        vs.load(parameters)?;
 
         vs.freeze();
 
-        let tagger = Tagger::new(device, model, biaffine_decoder, encoders);
+        let encoder_types = config
+            .labeler
+            .encoders
+            .iter()
+            .map(|encoder| (encoder.name.clone(), encoder.encoder.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let tagger = Tagger::new(options.device, model, decoders, encoder_types);
+
+        let max_len = match (options.max_len, model_max_len(config, pretrain_config)) {
+            (Some(max_len), Some(model_max_len)) => Some(cmp::min(max_len, model_max_len)),
+            (max_len, model_max_len) => max_len.or(model_max_len),
+        };
 
         Ok(Annotator {
+            batch_size: options.batch_size,
+            end_piece: has_end_piece(&config.input.tokenizer),
+            max_len,
+            n_layers: n_layers(pretrain_config),
+            piece_vocab,
             tagger: TaggerWrap(tagger),
             tokenizer,
         })
     }
 
+    /// Annotate sentences.
+    ///
+    /// If the batch size in `options` is 0, the default batch size of
+    /// the annotator is used. Requesting top-k labels, an annotation
+    /// policy, or annotation of a layer that the annotator does not
+    /// assign, or head scores from an annotator without a biaffine
+    /// parser results in an error.
     pub fn annotate_sentences(
         &self,
         sentences: impl IntoIterator<Item = Sentence>,
-        batch_size: usize,
-    ) -> Result<Vec<SentenceWithPieces>, AnnotatorError> where {
-        let mut sentences_with_pieces = sentences
+        options: &AnnotateOptions,
+    ) -> Result<Vec<AnnotatedSentence>, AnnotatorError> {
+        self.annotate_constrained_sentences(
+            sentences
+                .into_iter()
+                .map(|sentence| (sentence, SentenceConstraints::default())),
+            options,
+        )
+    }
+
+    /// Annotate sentences with decoding constraints.
+    ///
+    /// This method is like `annotate_sentences`, but every sentence is
+    /// decoded using its constraints. Constraints are only supported
+    /// for sequence labeling layers and can only use labels of the
+    /// model.
+    ///
+    /// When per-sentence errors are requested in `options`, errors that
+    /// concern a single sentence are stored in the annotated sentence.
+    /// A batch that fails is retried sentence by sentence, so that only
+    /// the sentences that cannot be annotated get an error.
+    pub fn annotate_constrained_sentences(
+        &self,
+        sentences: impl IntoIterator<Item = (Sentence, SentenceConstraints)>,
+        options: &AnnotateOptions,
+    ) -> Result<Vec<AnnotatedSentence>, AnnotatorError> {
+        self.check_annotate_options(options)?;
+
+        let (sentences, constraints): (Vec<_>, Vec<_>) = sentences.into_iter().unzip();
+        let mut errors = Vec::with_capacity(sentences.len());
+        for (sentence, sentence_constraints) in sentences.iter().zip(&constraints) {
+            errors.push(sentence_error(
+                self.check_sentence(sentence, sentence_constraints, options),
+                options,
+            )?);
+        }
+
+        let batch_size = self.batch_size(options.batch_size);
+        let mut sentences_with_pieces = self.tokenize_sentences(sentences);
+
+        // Sentences that were truncated, with their original sentence.
+        let mut truncated = vec![None; sentences_with_pieces.len()];
+
+        let mut window = None;
+        match (options.long_sentences, self.max_len) {
+            (_, None) => (),
+            (LongSentences::Reject, Some(max_len)) => {
+                for (sentence, error) in sentences_with_pieces.iter().zip(&mut errors) {
+                    if error.is_none() && sentence.pieces.len() > max_len {
+                        *error = sentence_error(
+                            Err(AnnotatorError::SequenceTooLong(
+                                sentence.pieces.len(),
+                                max_len,
+                            )),
+                            options,
+                        )?;
+                    }
+                }
+            }
+            (LongSentences::Truncate, Some(max_len)) => {
+                for (sentence, original) in sentences_with_pieces.iter_mut().zip(&mut truncated) {
+                    if sentence.pieces.len() > max_len {
+                        *original = Some(truncate_sentence(sentence, max_len));
+                    }
+                }
+            }
+            (LongSentences::SlidingWindow, Some(max_len)) => {
+                window = Some(SlidingWindow {
+                    max_len,
+                    overlap: options.window_overlap.unwrap_or(max_len / 2),
+                    end_piece: self.end_piece,
+                })
+            }
+        }
+
+        let mut scores = vec![SentenceScores::default(); sentences_with_pieces.len()];
+
+        // Sort sentences by length, skipping sentences with errors.
+        let mut sent_refs: Vec<_> = sentences_with_pieces
+            .iter_mut()
+            .enumerate()
+            .filter(|(idx, _)| errors[*idx].is_none())
+            .collect();
+        sent_refs.sort_unstable_by_key(|(_, s)| s.pieces.len());
+
+        // Split in batches, tag, and merge results.
+        let batch_lens = sent_refs
+            .iter()
+            .map(|(_, s)| s.pieces.len())
+            .collect::<Vec<_>>();
+        for batch in batches(&batch_lens, batch_size, options.max_batch_pieces) {
+            let batch = &mut sent_refs[batch];
+            if !options.per_sentence_errors {
+                self.tag_batch(batch, &constraints, window, options, &mut scores)?;
+                continue;
+            }
+
+            // Tagging can fail after some sentences were annotated, so
+            // the sentences are restored before they are tagged again.
+            let originals = batch
+                .iter()
+                .map(|(_, s)| s.sentence.clone())
+                .collect::<Vec<_>>();
+            if self
+                .tag_batch(batch, &constraints, window, options, &mut scores)
+                .is_ok()
+            {
+                continue;
+            }
+
+            for (sentence, original) in batch.iter_mut().zip(originals) {
+                sentence.1.sentence = original.clone();
+                if let Err(err) = self.tag_batch(
+                    slice::from_mut(sentence),
+                    &constraints,
+                    window,
+                    options,
+                    &mut scores,
+                ) {
+                    sentence.1.sentence = original;
+                    errors[sentence.0] = Some(err);
+                }
+            }
+        }
+
+        Ok(sentences_with_pieces
             .into_iter()
-            .map(|s| self.tokenizer.tokenize(s))
+            .zip(scores)
+            .zip(truncated)
+            .zip(errors)
+            .map(|(((sentence, mut scores), original), error)| {
+                let sentence = match original {
+                    Some(original) if error.is_some() => original,
+                    Some(original) => {
+                        scores.n_truncated_tokens = original.len() - sentence.sentence.len();
+                        restore_truncated(original, sentence.sentence)
+                    }
+                    None => sentence.sentence,
+                };
+
+                AnnotatedSentence {
+                    sentence,
+                    scores,
+                    error,
+                }
+            })
+            .collect())
+    }
+
+    /// Tag a batch of sentences and store their scores.
+    fn tag_batch(
+        &self,
+        batch: &mut [(usize, &mut SentenceWithPieces)],
+        constraints: &[SentenceConstraints],
+        window: Option<SlidingWindow>,
+        options: &AnnotateOptions,
+        scores: &mut [SentenceScores],
+    ) -> Result<(), AnnotatorError> {
+        let batch_constraints = batch
+            .iter()
+            .map(|(idx, _)| &constraints[*idx])
             .collect::<Vec<_>>();
+        let mut batch_sentences = batch.iter_mut().map(|(_, s)| &mut **s).collect::<Vec<_>>();
+        let batch_scores =
+            self.tagger
+                .tag_sentences(&mut batch_sentences, &batch_constraints, window, options)?;
+
+        for ((idx, _), sentence_scores) in batch.iter().zip(batch_scores) {
+            scores[*idx] = sentence_scores;
+        }
+
+        Ok(())
+    }
+
+    /// Check that a sentence can be annotated with its constraints.
+    fn check_sentence(
+        &self,
+        sentence: &Sentence,
+        constraints: &SentenceConstraints,
+        options: &AnnotateOptions,
+    ) -> Result<(), AnnotatorError> {
+        self.check_constraints(constraints)?;
+
+        if self.tagger.has_layer(HEAD)
+            && dependency_policy(options) == AnnotationPolicy::KeepIfPresent
+        {
+            check_fixed_dependencies(sentence)?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that annotate options can be used with this annotator.
+    pub(crate) fn check_annotate_options(
+        &self,
+        options: &AnnotateOptions,
+    ) -> Result<(), AnnotatorError> {
+        if let Some(unknown) = options
+            .top_k
+            .keys()
+            .chain(options.policies.keys())
+            .chain(options.layers.iter().flatten())
+            .find(|name| !self.tagger.has_layer(name))
+        {
+            return Err(AnnotatorError::UnknownEncoder(unknown.clone()));
+        }
+
+        if let (Some(head_policy), Some(relation_policy)) =
+            (options.policies.get(HEAD), options.policies.get(RELATION))
+        {
+            if head_policy != relation_policy {
+                return Err(AnnotatorError::InvalidOption(
+                    "head and relation must have the same annotation policy".to_string(),
+                ));
+            }
+        }
+
+        if options.head_scores != HeadScores::None && !self.tagger.has_layer(HEAD) {
+            return Err(AnnotatorError::InvalidOption(
+                "head scores require a biaffine parser".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that the allowed labels of constraints are labels of the model.
+    fn check_constraints(&self, constraints: &SentenceConstraints) -> Result<(), AnnotatorError> {
+        for (layer, allowed) in constraints.allowed_labels.iter().flatten() {
+            if !self.tagger.has_layer(layer) {
+                return Err(AnnotatorError::UnknownEncoder(layer.clone()));
+            }
+
+            let labels = self.tagger.sequence_labels(layer).ok_or_else(|| {
+                AnnotatorError::InvalidConstraint(format!(
+                    "allowed labels are only supported for sequence labeling layers, not {}",
+                    layer
+                ))
+            })?;
+
+            if allowed.is_empty() {
+                return Err(AnnotatorError::InvalidConstraint(format!(
+                    "a token has no allowed labels in layer {}",
+                    layer
+                )));
+            }
+
+            if let Some(unknown) = allowed.iter().find(|label| !labels.contains_key(*label)) {
+                return Err(AnnotatorError::InvalidConstraint(format!(
+                    "layer {} does not have label {}",
+                    layer, unknown
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compute token embeddings.
+    ///
+    /// Returns a matrix with shape `[n_tokens, dims]` for every sentence.
+    pub fn embed_sentences(
+        &self,
+        sentences: impl IntoIterator<Item = Sentence>,
+        options: &EmbedOptions,
+    ) -> Result<Vec<Array2<f32>>, AnnotatorError> {
+        Ok(self
+            .embed(sentences, options)?
+            .into_iter()
+            .map(|embeddings| embeddings.slice(s![1.., ..]).to_owned())
+            .collect())
+    }
+
+    /// Compute sentence embeddings.
+    ///
+    /// The token embeddings of each sentence are pooled using the pooling
+    /// method of `options`. Returns a matrix with shape
+    /// `[n_sentences, dims]`.
+    pub fn embed_sentences_pooled(
+        &self,
+        sentences: impl IntoIterator<Item = Sentence>,
+        options: &EmbedOptions,
+    ) -> Result<Array2<f32>, AnnotatorError> {
+        let embeddings = self.embed(sentences, options)?;
+        Ok(pool_sentences(&embeddings, options.pooling))
+    }
+
+    /// Compute embeddings, including the embedding of the root.
+    fn embed(
+        &self,
+        sentences: impl IntoIterator<Item = Sentence>,
+        options: &EmbedOptions,
+    ) -> Result<Vec<Array2<f32>>, AnnotatorError> {
+        let layer_weights = self.layer_weights(options)?;
+        let batch_size = self.batch_size(options.batch_size);
+        let mut sentences_with_pieces = self.tokenize(sentences)?;
+
+        let mut embeddings = vec![Array2::zeros((0, 0)); sentences_with_pieces.len()];
 
         // Sort sentences by length.
-        let mut sent_refs: Vec<_> = sentences_with_pieces.iter_mut().collect();
-        sent_refs.sort_unstable_by_key(|s| s.pieces.len());
+        let mut sent_refs: Vec<_> = sentences_with_pieces.iter_mut().enumerate().collect();
+        sent_refs.sort_unstable_by_key(|(_, s)| s.pieces.len());
 
-        // Split in batches, tag, and merge results.
         for batch in sent_refs.chunks_mut(batch_size) {
-            self.tagger.tag_sentences(batch)?;
+            let batch_sentences = batch.iter_mut().map(|(_, s)| &mut **s).collect::<Vec<_>>();
+            let batch_embeddings = self
+                .tagger
+                .embed_sentences(&batch_sentences, &layer_weights)?;
+
+            for ((idx, _), sentence_embeddings) in batch.iter().zip(batch_embeddings) {
+                embeddings[*idx] = sentence_embeddings;
+            }
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Get the vocabulary of the word pieces.
+    pub fn piece_vocab(&self) -> &PieceVocab {
+        &self.piece_vocab
+    }
+
+    /// Split sentences into word pieces without annotating them.
+    ///
+    /// In contrast to annotation, sentences that exceed the maximum
+    /// sequence length are not rejected.
+    pub fn tokenize_sentences(
+        &self,
+        sentences: impl IntoIterator<Item = Sentence>,
+    ) -> Vec<SentenceWithPieces> {
+        sentences
+            .into_iter()
+            .map(|s| self.tokenizer.tokenize(s))
+            .collect()
+    }
+
+    /// Get the batch size of a call, 0 selects the default batch size.
+    pub(crate) fn batch_size(&self, batch_size: usize) -> usize {
+        if batch_size == 0 {
+            self.batch_size
+        } else {
+            batch_size
         }
+    }
+
+    /// Resolve the layers of embed options to layer indices and weights.
+    fn layer_weights(&self, options: &EmbedOptions) -> Result<Vec<(usize, f32)>, AnnotatorError> {
+        let layers = if options.layers.is_empty() {
+            vec![-1]
+        } else {
+            options.layers.clone()
+        };
+
+        layers
+            .iter()
+            .enumerate()
+            .map(|(idx, &layer)| {
+                let resolved = if layer < 0 {
+                    self.n_layers as isize + layer
+                } else {
+                    layer
+                };
+
+                if resolved < 0 || resolved >= self.n_layers as isize {
+                    return Err(AnnotatorError::InvalidOption(format!(
+                        "layer {} does not exist, the model has {} layers",
+                        layer, self.n_layers
+                    )));
+                }
+
+                let weight = options
+                    .weights
+                    .get(idx)
+                    .copied()
+                    .unwrap_or(1. / layers.len() as f32);
 
+                Ok((resolved as usize, weight))
+            })
+            .collect()
+    }
+
+    /// Split sentences into pieces.
+    fn tokenize(
+        &self,
+        sentences: impl IntoIterator<Item = Sentence>,
+    ) -> Result<Vec<SentenceWithPieces>, AnnotatorError> {
+        let sentences_with_pieces = self.tokenize_sentences(sentences);
+        self.check_sequence_lengths(&sentences_with_pieces)?;
         Ok(sentences_with_pieces)
     }
+
+    /// Check that sentences do not exceed the maximum sequence length.
+    fn check_sequence_lengths(
+        &self,
+        sentences: &[SentenceWithPieces],
+    ) -> Result<(), AnnotatorError> {
+        if let Some(max_len) = self.max_len {
+            if let Some(sentence) = sentences.iter().find(|s| s.pieces.len() > max_len) {
+                return Err(AnnotatorError::SequenceTooLong(
+                    sentence.pieces.len(),
+                    max_len,
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Split length-sorted sentences into batches.
+///
+/// Batches contain at most `batch_size` sentences. When `max_pieces` is
+/// set, a batch is also ended before the number of sentences times the
+/// length of the longest sentence exceeds `max_pieces`. Every batch has
+/// at least one sentence.
+fn batches(
+    sentence_lens: &[usize],
+    batch_size: usize,
+    max_pieces: Option<usize>,
+) -> Vec<Range<usize>> {
+    let mut batches = Vec::new();
+    let mut start = 0;
+    for (idx, &len) in sentence_lens.iter().enumerate() {
+        let n_sentences = idx - start + 1;
+        let exceeds_max_pieces = max_pieces
+            .map(|max_pieces| n_sentences * len > max_pieces)
+            .unwrap_or(false);
+        if n_sentences > batch_size || (n_sentences > 1 && exceeds_max_pieces) {
+            batches.push(start..idx);
+            start = idx;
+        }
+    }
+
+    if start < sentence_lens.len() {
+        batches.push(start..sentence_lens.len());
+    }
+
+    batches
+}
+
+/// Get the error of a sentence.
+///
+/// The error is returned as the error of the sentence when per-sentence
+/// errors are requested and as the error of the annotate call otherwise.
+fn sentence_error(
+    result: Result<(), AnnotatorError>,
+    options: &AnnotateOptions,
+) -> Result<Option<AnnotatorError>, AnnotatorError> {
+    match result {
+        Ok(()) => Ok(None),
+        Err(err) if options.per_sentence_errors => Ok(Some(err)),
+        Err(err) => Err(err),
+    }
+}
+
+/// Check that the dependency relations of a sentence can be part of a tree.
+///
+/// The biaffine parser keeps the relations that are present in a
+/// sentence, which is only possible if they do not form a cycle.
+fn check_fixed_dependencies(sentence: &Sentence) -> Result<(), AnnotatorError> {
+    let dep_graph = sentence.dep_graph();
+
+    for dependent in 1..sentence.len() {
+        // Follow the heads until the root or an unattached token is
+        // reached. A path that is longer than the sentence ends in a
+        // cycle, which is reported when starting from one of its tokens.
+        let mut node = dependent;
+        for _ in 0..sentence.len() {
+            node = match dep_graph.head(node) {
+                Some(triple) => triple.head(),
+                None => break,
+            };
+
+            if node == dependent {
+                return Err(AnnotatorError::InvalidConstraint(format!(
+                    "the dependency relation of token {} is part of a cycle",
+                    dependent
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Truncate a sentence to the tokens that fit in `max_len` pieces.
+///
+/// At least one token is kept, its pieces are truncated when they do
+/// not fit. Returns the original sentence.
+fn truncate_sentence(sentence: &mut SentenceWithPieces, max_len: usize) -> Sentence {
+    let n_pieces = sentence.pieces.len();
+    let token_offsets = &sentence.token_offsets;
+    let token_end = |token: usize| token_offsets.get(token + 1).copied().unwrap_or(n_pieces);
+
+    let n_tokens = cmp::max(
+        1,
+        (0..token_offsets.len())
+            .take_while(|&token| token_end(token) <= max_len)
+            .count(),
+    );
+    let n_pieces = cmp::min(token_end(n_tokens - 1), max_len);
+
+    sentence.pieces = sentence.pieces.slice(s![..n_pieces]).to_owned();
+    sentence.token_offsets.truncate(n_tokens);
+
+    // Keep the dependency relations between the remaining tokens.
+    let mut truncated: Sentence = sentence.sentence.tokens().take(n_tokens).cloned().collect();
+    let dep_graph = sentence.sentence.dep_graph();
+    for dependent in 1..=n_tokens {
+        if let Some(triple) = dep_graph.head(dependent) {
+            if triple.head() <= n_tokens {
+                truncated.dep_graph_mut().add_deprel(DepTriple::new(
+                    triple.head(),
+                    triple.relation().map(ToOwned::to_owned),
+                    dependent,
+                ));
+            }
+        }
+    }
+
+    mem::replace(&mut sentence.sentence, truncated)
+}
+
+/// Copy the annotations of a truncated sentence to the original sentence.
+fn restore_truncated(mut original: Sentence, truncated: Sentence) -> Sentence {
+    let dep_graph = truncated.dep_graph();
+    for dependent in 1..truncated.len() {
+        if let Some(triple) = dep_graph.head(dependent) {
+            original.dep_graph_mut().add_deprel(DepTriple::new(
+                triple.head(),
+                triple.relation().map(ToOwned::to_owned),
+                dependent,
+            ));
+        }
+    }
+
+    for (token, annotated) in original.tokens_mut().zip(truncated.tokens()) {
+        *token = annotated.clone();
+    }
+
+    original
+}
+
+/// The maximum number of pieces that the model can encode.
+///
+/// Returns `None` if the model does not have a maximum, which is the
+/// case for sinusoidal position embeddings.
+fn model_max_len(config: &Config, pretrain_config: &PretrainConfig) -> Option<usize> {
+    if config.model.position_embeddings != PositionEmbeddings::Model {
+        return None;
+    }
+
+    let max_position_embeddings = match pretrain_config {
+        PretrainConfig::Albert(config) => config.max_position_embeddings,
+        PretrainConfig::Bert(config) => config.max_position_embeddings,
+        PretrainConfig::SqueezeAlbert(config) => config.max_position_embeddings,
+        PretrainConfig::SqueezeBert(config) => config.max_position_embeddings,
+        // Positions start after the padding index.
+        PretrainConfig::XlmRoberta(config) => {
+            config.max_position_embeddings - XLM_ROBERTA_POSITION_OFFSET
+        }
+    };
+
+    Some(max_position_embeddings as usize)
+}
+
+/// The number of layers of a model, including the embedding layer.
+fn n_layers(pretrain_config: &PretrainConfig) -> usize {
+    let n_hidden_layers = match pretrain_config {
+        PretrainConfig::Albert(config) => config.num_hidden_layers,
+        PretrainConfig::Bert(config) | PretrainConfig::XlmRoberta(config) => {
+            config.num_hidden_layers
+        }
+        PretrainConfig::SqueezeAlbert(config) => config.num_hidden_layers,
+        PretrainConfig::SqueezeBert(config) => config.num_hidden_layers,
+    };
+
+    n_hidden_layers as usize + 1
+}
+
+/// Check whether the file at `path` is a ZIP archive.
+fn is_archive(path: &Path) -> bool {
+    let mut magic = [0u8; 4];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .map(|_| &magic == ZIP_MAGIC)
+        .unwrap_or(false)
 }
 
 pub fn load_pretrain_config(config: &Config) -> Result<PretrainConfig, AnnotatorError> {
     Ok(config.model.pretrain_config()?)
 }
 
-fn load_biaffine_decoder(
-    config: &BiaffineParserConfig,
-) -> Result<ImmutableDependencyEncoder, AnnotatorError> {
+fn read_pretrain_config(config: &Config, data: &[u8]) -> Result<PretrainConfig, AnnotatorError> {
+    let json_err = |model: &str, err| {
+        SyntaxDotError::JSonSerialization(format!("Cannot read model {} config", model), err)
+    };
+
+    Ok(match config.model.pretrain_type {
+        PretrainModelType::Albert => PretrainConfig::Albert(
+            serde_json::from_slice(data).map_err(|err| json_err("ALBERT", err))?,
+        ),
+        PretrainModelType::Bert => {
+            PretrainConfig::Bert(serde_json::from_slice(data).map_err(|err| json_err("BERT", err))?)
+        }
+        PretrainModelType::SqueezeAlbert => PretrainConfig::SqueezeAlbert(
+            serde_json::from_slice(data).map_err(|err| json_err("SqueezeALBERT", err))?,
+        ),
+        PretrainModelType::SqueezeBert => PretrainConfig::SqueezeBert(
+            serde_json::from_slice(data).map_err(|err| json_err("SqueezeBERT", err))?,
+        ),
+        PretrainModelType::XlmRoberta => PretrainConfig::XlmRoberta(
+            serde_json::from_slice(data).map_err(|err| json_err("XLM-RoBERTa", err))?,
+        ),
+    })
+}
+
+/// Get the biaffine parser configuration, if the parser is enabled.
+fn biaffine_config<'a>(
+    config: &'a Config,
+    options: &AnnotatorOptions,
+) -> Option<&'a BiaffineParserConfig> {
+    config.biaffine.as_ref().filter(|_| options.biaffine)
+}
+
+fn load_biaffine_decoder(config: &BiaffineParserConfig) -> Result<BiaffineDecoder, AnnotatorError> {
     let f = File::open(&config.labels).map_err(|err| {
         AnnotatorError::Io(
             format!("Cannot open biaffine label file: {}", config.labels),
@@ -133,13 +940,53 @@ fn load_biaffine_decoder(
         )
     })?;
 
-    let encoder: ImmutableDependencyEncoder = serde_yaml::from_reader(&f)
-        .map_err(|err| AnnotatorError::LoadEncoders(config.labels.clone(), err))?;
+    read_biaffine_decoder(f, &config.labels)
+}
 
-    Ok(encoder)
+/// The serialized relation inventory of a biaffine decoder.
+#[derive(Deserialize)]
+struct BiaffineLabels {
+    relations: SerializedNumberer<String>,
 }
 
-fn load_encoders(config: &Config) -> Result<Encoders, AnnotatorError> {
+fn read_biaffine_decoder(read: impl Read, name: &str) -> Result<BiaffineDecoder, AnnotatorError> {
+    let labels: serde_yaml::Value = serde_yaml::from_reader(read)
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+
+    // The relation inventory of the decoder is not public, so it is
+    // read from the labels as well.
+    let relations: BiaffineLabels = serde_yaml::from_value(labels.clone())
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+    let decoder: ImmutableDependencyEncoder = serde_yaml::from_value(labels)
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+
+    // The model predicts relations by their index in the inventory.
+    let relations = relations.relations;
+    if relations.start_at != 0 || relations.values.len() != decoder.n_relations() {
+        return Err(AnnotatorError::InvalidLabels(
+            name.to_string(),
+            "the relations must be numbered from 0".to_string(),
+        ));
+    }
+
+    Ok(BiaffineDecoder {
+        decoder,
+        relations: relations.values,
+    })
+}
+
+/// Sequence labeling encoders with their label inventories.
+type EncodersWithLabels = (Encoders, HashMap<String, EncoderLabels>);
+
+/// Load the sequence labeling encoders.
+///
+/// If `enabled` is not `None`, only the encoders with the given names
+/// are loaded. The label inventories of the encoders are returned
+/// with the encoders.
+fn load_encoders(
+    config: &Config,
+    enabled: Option<&HashSet<String>>,
+) -> Result<EncodersWithLabels, AnnotatorError> {
     let f = File::open(&config.labeler.labels).map_err(|err| {
         AnnotatorError::Io(
             format!("Cannot open label file: {}", config.labeler.labels),
@@ -147,10 +994,185 @@ fn load_encoders(config: &Config) -> Result<Encoders, AnnotatorError> {
         )
     })?;
 
-    serde_yaml::from_reader(&f)
-        .map_err(|err| AnnotatorError::LoadEncoders(config.labeler.labels.clone(), err))
+    read_encoders(config, f, &config.labeler.labels, enabled)
+}
+
+fn read_encoders(
+    config: &Config,
+    read: impl Read,
+    name: &str,
+    enabled: Option<&HashSet<String>>,
+) -> Result<EncodersWithLabels, AnnotatorError> {
+    let mut encoders: serde_yaml::Value = serde_yaml::from_reader(read)
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+
+    if let Some(enabled) = enabled {
+        retain_encoders(config, &mut encoders, enabled)?;
+    }
+
+    let loaded = serde_yaml::from_value(encoders.clone())
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+    let labels = read_encoder_labels(&encoders, name, &loaded)?;
+
+    Ok((loaded, labels))
+}
+
+/// Retain the encoders with the given names in serialized encoders.
+fn retain_encoders(
+    config: &Config,
+    encoders: &mut serde_yaml::Value,
+    enabled: &HashSet<String>,
+) -> Result<(), AnnotatorError> {
+    if let Some(unknown) = enabled
+        .iter()
+        .find(|name| !config.labeler.encoders.iter().any(|e| &e.name == *name))
+    {
+        return Err(AnnotatorError::UnknownEncoder(unknown.clone()));
+    }
+
+    // Encoders are serialized as a sequence of named encoders.
+    let encoders = match encoders {
+        serde_yaml::Value::Sequence(encoders) => encoders,
+        _ => {
+            return Err(AnnotatorError::InvalidOption(
+                "encoders can only be selected from a sequence of named encoders".to_string(),
+            ))
+        }
+    };
+
+    encoders.retain(|encoder| {
+        encoder
+            .get("name")
+            .and_then(serde_yaml::Value::as_str)
+            .map(|name| enabled.contains(name))
+            .unwrap_or(false)
+    });
+
+    Ok(())
 }
 
 pub fn load_tokenizer(config: &Config) -> Result<Box<dyn Tokenize>, AnnotatorError> {
     Ok(config.tokenizer()?)
 }
+
+fn load_piece_vocab(config: &Config) -> Result<PieceVocab, AnnotatorError> {
+    let vocab_path = tokenizer_vocab(&config.input.tokenizer);
+    let vocab = fs::read(vocab_path).map_err(|err| {
+        AnnotatorError::Io(format!("Cannot read vocabulary: {}", vocab_path), err)
+    })?;
+
+    PieceVocab::read(&config.input.tokenizer, &vocab)
+}
+
+fn read_tokenizer(config: &Config, vocab: &[u8]) -> Result<Box<dyn Tokenize>, AnnotatorError> {
+    Ok(tokenizer_from_vocab(&config.input.tokenizer, vocab).map_err(SyntaxDotError::from)?)
+}
+
+/// Check whether the tokenizer ends sentences with a separate piece.
+fn has_end_piece(tokenizer: &Tokenizer) -> bool {
+    match tokenizer {
+        Tokenizer::Albert { .. } | Tokenizer::XlmRoberta { .. } => true,
+        Tokenizer::Bert { .. } => false,
+    }
+}
+
+/// Get the vocabulary file of a tokenizer.
+fn tokenizer_vocab(tokenizer: &Tokenizer) -> &str {
+    match tokenizer {
+        Tokenizer::Albert { vocab } => vocab,
+        Tokenizer::Bert { vocab } => vocab,
+        Tokenizer::XlmRoberta { vocab } => vocab,
+    }
+}
+
+fn tokenizer_from_vocab(
+    tokenizer: &Tokenizer,
+    vocab: &[u8],
+) -> Result<Box<dyn Tokenize>, TokenizerError> {
+    Ok(match tokenizer {
+        Tokenizer::Albert { .. } => Box::new(AlbertTokenizer::from(
+            SentencePieceProcessor::from_serialized_proto(vocab)?,
+        )),
+        Tokenizer::Bert { .. } => Box::new(BertTokenizer::read(vocab, "[UNK]")?),
+        Tokenizer::XlmRoberta { .. } => Box::new(XlmRobertaTokenizer::from(
+            SentencePieceProcessor::from_serialized_proto(vocab)?,
+        )),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use ffi_support::ErrorCode;
+    use udgraph::graph::{DepTriple, Sentence};
+    use udgraph::token::Token;
+
+    use super::{batches, check_fixed_dependencies, read_biaffine_decoder};
+    use crate::error::error_codes::{INVALID_CONSTRAINT_ERROR, LOAD_ENCODERS_ERROR};
+
+    fn test_sentence(triples: &[(usize, usize)]) -> Sentence {
+        let mut sentence = Sentence::from_iter(
+            ["Dit", "is", "een", "test"]
+                .iter()
+                .map(|&form| Token::new(form)),
+        );
+        for &(head, dependent) in triples {
+            sentence
+                .dep_graph_mut()
+                .add_deprel(DepTriple::new(head, Some("dep"), dependent));
+        }
+        sentence
+    }
+
+    #[test]
+    fn fixed_dependencies_can_be_partial() {
+        assert!(check_fixed_dependencies(&test_sentence(&[])).is_ok());
+        assert!(check_fixed_dependencies(&test_sentence(&[(4, 1), (4, 3)])).is_ok());
+        assert!(check_fixed_dependencies(&test_sentence(&[(0, 4), (4, 1), (1, 2)])).is_ok());
+    }
+
+    #[test]
+    fn fixed_dependencies_cannot_form_a_cycle() {
+        let err = check_fixed_dependencies(&test_sentence(&[(2, 1), (1, 2)])).unwrap_err();
+        assert_eq!(
+            ErrorCode::from(&err),
+            ErrorCode::new(INVALID_CONSTRAINT_ERROR)
+        );
+
+        // Tokens that are attached to a cycle do not form a tree either.
+        assert!(
+            check_fixed_dependencies(&test_sentence(&[(3, 2), (4, 3), (2, 4), (2, 1)])).is_err()
+        );
+    }
+
+    #[test]
+    fn biaffine_relations_are_read_from_labels() {
+        let labels = "relations:\n  values:\n    - root\n    - obj\n  start_at: 0\n";
+        let decoder = read_biaffine_decoder(labels.as_bytes(), "labels").unwrap();
+        assert_eq!(decoder.relations, vec!["root", "obj"]);
+        assert_eq!(decoder.decoder.n_relations(), 2);
+
+        let labels = "relations:\n  values:\n    - root\n  start_at: 1\n";
+        let err = read_biaffine_decoder(labels.as_bytes(), "labels").unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(LOAD_ENCODERS_ERROR));
+    }
+
+    #[test]
+    fn batches_are_limited_by_pieces() {
+        assert_eq!(batches(&[1, 2, 3, 4, 5], 2, None), vec![0..2, 2..4, 4..5]);
+
+        // Padding counts towards the limit, a sentence that exceeds
+        // the limit gets its own batch.
+        assert_eq!(
+            batches(&[2, 2, 3, 5, 10, 20], 32, Some(10)),
+            vec![0..3, 3..4, 4..5, 5..6]
+        );
+        assert_eq!(
+            batches(&[2, 2, 3, 5, 10, 20], 2, Some(10)),
+            vec![0..2, 2..4, 4..5, 5..6]
+        );
+
+        assert!(batches(&[], 32, Some(10)).is_empty());
+    }
+}
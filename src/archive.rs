@@ -0,0 +1,267 @@
+//! Packaged model archives.
+//!
+//! A model archive is a ZIP file that contains all the files of a
+//! model, plus a manifest named `manifest.toml` in the root of the
+//! archive. The manifest specifies the syntaxdot configuration file and
+//! the SHA-256 checksum of every member that is used:
+//!
+//! ```toml
+//! config = "syntaxdot.conf"
+//!
+//! [checksums]
+//! "syntaxdot.conf" = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08"
+//! "params" = "..."
+//! ```
+//!
+//! File names in the configuration are resolved relative to the
+//! directory of the configuration file within the archive.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::AnnotatorError;
+
+/// The name of the manifest file.
+const MANIFEST: &str = "manifest.toml";
+
+/// The size of the buffer that is used to copy members.
+const COPY_BUFFER_SIZE: usize = 64 * 1024;
+
+/// The archive manifest.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+    /// The syntaxdot configuration file.
+    config: String,
+
+    /// SHA-256 checksums of the archive members.
+    checksums: BTreeMap<String, String>,
+}
+
+/// A model archive.
+pub struct ModelArchive {
+    archive: ZipArchive<BufReader<File>>,
+    manifest: Manifest,
+}
+
+impl ModelArchive {
+    /// Open a model archive and read its manifest.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AnnotatorError> {
+        let path = path.as_ref();
+        let f = File::open(path).map_err(|err| {
+            AnnotatorError::Io(
+                format!("Cannot open model archive `{}`", path.to_string_lossy()),
+                err,
+            )
+        })?;
+
+        let mut archive = ZipArchive::new(BufReader::new(f))
+            .map_err(|err| AnnotatorError::Archive(err.to_string()))?;
+
+        let manifest = read_member_unverified(&mut archive, MANIFEST)?;
+        let manifest = std::str::from_utf8(&manifest)
+            .map_err(|err| AnnotatorError::Archive(format!("Cannot read manifest: {}", err)))?;
+        let manifest: Manifest = toml::from_str(manifest)
+            .map_err(|err| AnnotatorError::Archive(format!("Cannot read manifest: {}", err)))?;
+
+        Ok(ModelArchive { archive, manifest })
+    }
+
+    /// The name of the configuration member.
+    pub fn config(&self) -> &str {
+        &self.manifest.config
+    }
+
+    /// Read a member that is referenced by the configuration file.
+    pub fn read_config_relative(&mut self, name: &str) -> Result<Vec<u8>, AnnotatorError> {
+        let mut data = Vec::new();
+        self.copy_config_relative(name, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copy a member that is referenced by the configuration file.
+    ///
+    /// See [`ModelArchive::copy`].
+    pub fn copy_config_relative(
+        &mut self,
+        name: &str,
+        writer: &mut impl Write,
+    ) -> Result<(), AnnotatorError> {
+        let name = match self.manifest.config.rfind('/') {
+            Some(idx) => format!("{}/{}", &self.manifest.config[..idx], name),
+            None => name.to_string(),
+        };
+
+        self.copy(&name, writer)
+    }
+
+    /// Read a member and verify its checksum.
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, AnnotatorError> {
+        let mut data = Vec::new();
+        self.copy(name, &mut data)?;
+        Ok(data)
+    }
+
+    /// Copy a member to `writer` and verify its checksum.
+    ///
+    /// The member is streamed, so that large members such as the model
+    /// parameters are not buffered. Since the checksum can only be
+    /// verified after copying, the data that was written must be
+    /// discarded when an error is returned.
+    pub fn copy(&mut self, name: &str, writer: &mut impl Write) -> Result<(), AnnotatorError> {
+        let name = name.trim_start_matches("./");
+
+        let checksum = self
+            .manifest
+            .checksums
+            .get(name)
+            .ok_or_else(|| AnnotatorError::ArchiveMissingMember(name.to_string()))?;
+
+        let mut member = self
+            .archive
+            .by_name(name)
+            .map_err(|err| member_error(name, err))?;
+
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; COPY_BUFFER_SIZE];
+        loop {
+            let n = match member.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    return Err(AnnotatorError::ArchiveCorruptMember(
+                        name.to_string(),
+                        err.to_string(),
+                    ))
+                }
+            };
+
+            hasher.update(&buf[..n]);
+            writer.write_all(&buf[..n]).map_err(|err| {
+                AnnotatorError::Io(format!("Cannot extract archive member `{}`", name), err)
+            })?;
+        }
+
+        if !format!("{:x}", hasher.finalize()).eq_ignore_ascii_case(checksum) {
+            return Err(AnnotatorError::ArchiveCorruptMember(
+                name.to_string(),
+                "checksum mismatch".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Map an error that occurs while opening the member `name`.
+fn member_error(name: &str, err: ZipError) -> AnnotatorError {
+    match err {
+        ZipError::FileNotFound => AnnotatorError::ArchiveMissingMember(name.to_string()),
+        err => AnnotatorError::ArchiveCorruptMember(name.to_string(), err.to_string()),
+    }
+}
+
+/// Read a member without verifying the manifest checksum.
+///
+/// The CRC-32 checksum of the ZIP member is still verified.
+fn read_member_unverified(
+    archive: &mut ZipArchive<BufReader<File>>,
+    name: &str,
+) -> Result<Vec<u8>, AnnotatorError> {
+    let mut member = archive
+        .by_name(name)
+        .map_err(|err| member_error(name, err))?;
+
+    let mut data = Vec::with_capacity(member.size() as usize);
+    member.read_to_end(&mut data).map_err(|err: io::Error| {
+        AnnotatorError::ArchiveCorruptMember(name.to_string(), err.to_string())
+    })?;
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use ffi_support::ErrorCode;
+    use sha2::{Digest, Sha256};
+    use tempfile::NamedTempFile;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::{ModelArchive, MANIFEST};
+    use crate::error::error_codes::{ARCHIVE_CORRUPT_MEMBER_ERROR, ARCHIVE_MISSING_MEMBER_ERROR};
+
+    const CONFIG: &str = "[model]\nparameters = \"params\"\n";
+
+    fn write_archive(config_checksum: &str) -> NamedTempFile {
+        let manifest = format!(
+            "config = \"syntaxdot.conf\"\n\n[checksums]\n\"syntaxdot.conf\" = \"{}\"\n\"params\" = \"{}\"\n",
+            config_checksum,
+            sha256_hex(b"params")
+        );
+
+        let mut archive_file = NamedTempFile::new().unwrap();
+        let mut zip = ZipWriter::new(archive_file.as_file_mut());
+        for (name, data) in &[(MANIFEST, manifest.as_str()), ("syntaxdot.conf", CONFIG)] {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+        drop(zip);
+        archive_file
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    #[test]
+    fn members_are_read() {
+        let archive_file = write_archive(&sha256_hex(CONFIG.as_bytes()));
+        let mut archive = ModelArchive::open(archive_file.path()).unwrap();
+        assert_eq!(archive.config(), "syntaxdot.conf");
+        assert_eq!(archive.read("./syntaxdot.conf").unwrap(), CONFIG.as_bytes());
+    }
+
+    #[test]
+    fn missing_member_is_rejected() {
+        let archive_file = write_archive(&sha256_hex(CONFIG.as_bytes()));
+        let mut archive = ModelArchive::open(archive_file.path()).unwrap();
+
+        // The member is in the manifest, but not in the archive.
+        let err = archive.read_config_relative("params").unwrap_err();
+        assert_eq!(
+            ErrorCode::from(&err),
+            ErrorCode::new(ARCHIVE_MISSING_MEMBER_ERROR)
+        );
+
+        // The member is neither in the manifest, nor in the archive.
+        let err = archive.read_config_relative("vocab.txt").unwrap_err();
+        assert_eq!(
+            ErrorCode::from(&err),
+            ErrorCode::new(ARCHIVE_MISSING_MEMBER_ERROR)
+        );
+    }
+
+    #[test]
+    fn corrupt_member_is_rejected() {
+        let archive_file = write_archive(&sha256_hex(b"corrupt"));
+        let mut archive = ModelArchive::open(archive_file.path()).unwrap();
+
+        let err = archive.read("syntaxdot.conf").unwrap_err();
+        assert_eq!(
+            ErrorCode::from(&err),
+            ErrorCode::new(ARCHIVE_CORRUPT_MEMBER_ERROR)
+        );
+    }
+}
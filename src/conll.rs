@@ -0,0 +1,340 @@
+//! CoNLL-U input and output.
+//!
+//! The `conllu` reader and writer do not support multiword tokens and
+//! empty nodes. These lines are separated from the regular token lines
+//! before reading and are reinserted after writing.
+
+use std::mem;
+
+use conllu::io::{ReadSentence, Reader, WriteSentence, Writer};
+use conllu::{IOError, ParseError};
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Token;
+
+use crate::constraints::SentenceConstraints;
+use crate::sentences::{EmptyNode, ExtendedSentence, MultiwordToken};
+use crate::AnnotatorError;
+
+/// Read sentences from CoNLL-U text.
+pub fn read_sentences(text: &str) -> Result<Vec<ExtendedSentence>, AnnotatorError> {
+    let mut token_lines = String::new();
+    let mut extras = Vec::new();
+    let mut multiword_tokens = Vec::new();
+    let mut empty_nodes = Vec::new();
+    let mut has_tokens = false;
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            // Sentences without tokens are skipped by the reader.
+            if has_tokens {
+                extras.push((
+                    mem::take(&mut multiword_tokens),
+                    mem::take(&mut empty_nodes),
+                ));
+                has_tokens = false;
+            }
+        } else if !line.starts_with('#') {
+            let line = line.trim();
+            let (id, fields) = line.split_at(line.find('\t').unwrap_or(0));
+            if let Some(idx) = id.find('-') {
+                let token = read_token(line, fields)?;
+                multiword_tokens.push(MultiwordToken {
+                    first: parse_id(line, &id[..idx])?,
+                    last: parse_id(line, &id[idx + 1..])?,
+                    form: token.form().to_string(),
+                    misc: token.misc().clone(),
+                });
+                continue;
+            } else if let Some(idx) = id.find('.') {
+                empty_nodes.push(EmptyNode {
+                    position: parse_id(line, &id[..idx])?,
+                    index: parse_id(line, &id[idx + 1..])?,
+                    token: read_token(line, fields)?,
+                });
+                continue;
+            }
+
+            has_tokens = true;
+        }
+
+        token_lines.push_str(line);
+        token_lines.push('\n');
+    }
+
+    if has_tokens {
+        extras.push((multiword_tokens, empty_nodes));
+    }
+
+    let mut reader = Reader::new(token_lines.as_bytes());
+    let mut sentences = Vec::new();
+    for (multiword_tokens, empty_nodes) in extras {
+        let sentence = reader.read_sentence()?.ok_or_else(|| {
+            AnnotatorError::ConlluParse("text contains fewer sentences than expected".to_string())
+        })?;
+        sentences.push(ExtendedSentence {
+            sentence,
+            multiword_tokens,
+            empty_nodes,
+            constraints: SentenceConstraints::default(),
+        });
+    }
+
+    Ok(sentences)
+}
+
+/// Read the token of a multiword token or empty node line.
+///
+/// `fields` are the fields of the line after the identifier, including
+/// the leading tab.
+fn read_token(line: &str, fields: &str) -> Result<Token, AnnotatorError> {
+    let token_line = format!("1{}\n", fields);
+    let mut reader = Reader::new(token_line.as_bytes());
+
+    let mut sentence = reader.read_sentence()?.ok_or_else(|| {
+        AnnotatorError::ConlluParse(format!("line does not contain a token: {}", line))
+    })?;
+    let token = sentence
+        .iter_mut()
+        .find_map(Node::token_mut)
+        .ok_or_else(|| {
+            AnnotatorError::ConlluParse(format!("line does not contain a token: {}", line))
+        })?;
+
+    Ok(mem::replace(token, Token::new("")))
+}
+
+fn parse_id(line: &str, id: &str) -> Result<usize, AnnotatorError> {
+    id.parse().map_err(|_| {
+        IOError::Parse(ParseError::ParseIdentifierField {
+            value: line.to_string(),
+        })
+        .into()
+    })
+}
+
+/// Write sentences as CoNLL-U text.
+///
+/// Every sentence, including the last, is terminated by an empty line.
+pub fn write_sentences<'a>(
+    sentences: impl IntoIterator<Item = &'a ExtendedSentence>,
+) -> Result<String, AnnotatorError> {
+    let mut text = String::new();
+
+    for sentence in sentences {
+        let sentence_text = write_sentence(&sentence.sentence)?;
+
+        for line in sentence_text.lines() {
+            let position = if line.starts_with('#') {
+                None
+            } else {
+                line.split('\t').next().and_then(|id| id.parse().ok())
+            };
+
+            if let Some(position) = position {
+                // Empty nodes that precede the first token.
+                if position == 1 {
+                    write_empty_nodes(&mut text, sentence, 0)?;
+                }
+
+                for multiword_token in sentence
+                    .multiword_tokens
+                    .iter()
+                    .filter(|token| token.first == position)
+                {
+                    write_multiword_token(&mut text, multiword_token)?;
+                }
+            }
+
+            text.push_str(line);
+            text.push('\n');
+
+            if let Some(position) = position {
+                write_empty_nodes(&mut text, sentence, position)?;
+            }
+        }
+
+        text.push('\n');
+    }
+
+    Ok(text)
+}
+
+fn write_sentence(sentence: &Sentence) -> Result<String, AnnotatorError> {
+    let mut data = Vec::new();
+    Writer::new(&mut data).write_sentence(sentence)?;
+    Ok(String::from_utf8(data).expect("CoNLL-U writer produced invalid UTF-8"))
+}
+
+/// Write a token line, replacing the identifier by `id`.
+fn write_token_line(text: &mut String, id: &str, token: &Token) -> Result<(), AnnotatorError> {
+    let mut sentence = Sentence::new();
+    sentence.push(token.clone());
+
+    let line = write_sentence(&sentence)?;
+    text.push_str(id);
+    text.push_str(&line[line.find('\t').unwrap_or(0)..]);
+
+    Ok(())
+}
+
+fn write_multiword_token(
+    text: &mut String,
+    multiword_token: &MultiwordToken,
+) -> Result<(), AnnotatorError> {
+    let mut token = Token::new(multiword_token.form.clone());
+    token.set_misc(multiword_token.misc.clone());
+
+    write_token_line(
+        text,
+        &format!("{}-{}", multiword_token.first, multiword_token.last),
+        &token,
+    )
+}
+
+fn write_empty_nodes(
+    text: &mut String,
+    sentence: &ExtendedSentence,
+    position: usize,
+) -> Result<(), AnnotatorError> {
+    for empty_node in sentence
+        .empty_nodes
+        .iter()
+        .filter(|node| node.position == position)
+    {
+        write_token_line(
+            text,
+            &format!("{}.{}", empty_node.position, empty_node.index),
+            &empty_node.token,
+        )?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::iter::FromIterator;
+
+    use ffi_support::{ErrorCode, ExternError};
+    use udgraph::token::Misc;
+
+    use super::{read_sentences, write_sentences};
+    use crate::error::error_codes::CONLLU_ERROR;
+    use crate::sentences::{proto, ExtendedSentence, MultiwordToken};
+
+    #[test]
+    fn multiword_tokens_and_empty_nodes_round_trip() {
+        let conllu = "1-2\tdu\t_\t_\t_\t_\t_\t_\t_\tSpaceAfter=No\n\
+                      1\tde\tde\tADP\t_\t_\t_\t_\t_\t_\n\
+                      2\tle\tle\tDET\t_\t_\t_\t_\t_\t_\n\
+                      3\tpain\tpain\tNOUN\t_\t_\t_\t_\t_\t_\n\
+                      3.1\tmange\tmanger\tVERB\t_\t_\t_\t_\t0:root\t_\n\n";
+
+        let sentences = read_sentences(conllu).unwrap();
+        assert_eq!(sentences.len(), 1);
+        assert_eq!(sentences[0].sentence.len(), 4);
+        assert_eq!(
+            sentences[0].multiword_tokens,
+            vec![MultiwordToken {
+                first: 1,
+                last: 2,
+                form: "du".to_string(),
+                misc: Misc::from_iter(vec![("SpaceAfter", Some("No"))]),
+            }]
+        );
+        assert_eq!(sentences[0].empty_nodes.len(), 1);
+        assert_eq!(sentences[0].empty_nodes[0].position, 3);
+        assert_eq!(sentences[0].empty_nodes[0].index, 1);
+        assert_eq!(sentences[0].empty_nodes[0].token.lemma(), Some("manger"));
+        assert_eq!(sentences[0].empty_nodes[0].token.deps(), Some("0:root"));
+
+        assert_eq!(write_sentences(&sentences).unwrap(), conllu);
+
+        let proto_sentence = proto::Sentence::from(sentences[0].clone());
+        assert_eq!(
+            ExtendedSentence::try_from(proto_sentence).unwrap(),
+            sentences[0]
+        );
+    }
+
+    #[test]
+    fn conllu_round_trips() {
+        let conllu = "# sent_id = 1\n1\tDit\tdit\tPRON\t_\tPerson=3\t2\tnsubj\t_\t_\n2\tis\tzijn\tAUX\t_\t_\t0\troot\t_\t_\n\n";
+        let sentences = read_sentences(conllu).unwrap();
+        assert_eq!(sentences.len(), 1);
+        assert_eq!(write_sentences(&sentences).unwrap(), conllu);
+    }
+
+    #[test]
+    fn invalid_conllu_is_rejected() {
+        let err: ExternError = read_sentences("1\tDit\t_\t_\t_\t_\tfoo\t_\t_\t_\n\n")
+            .unwrap_err()
+            .into();
+        assert_eq!(err.get_code(), ErrorCode::new(CONLLU_ERROR));
+    }
+
+    #[test]
+    fn malformed_multiword_tokens_and_empty_nodes_are_rejected() {
+        for conllu in &[
+            "1-2\n1\tDe\t_\t_\t_\t_\t_\t_\t_\t_\n\n",
+            "1-2\t\n1\tDe\t_\t_\t_\t_\t_\t_\t_\t_\n\n",
+            "1-x\tdu\t_\t_\t_\t_\t_\t_\t_\t_\n1\tDe\t_\t_\t_\t_\t_\t_\t_\t_\n\n",
+            "1\tDe\t_\t_\t_\t_\t_\t_\t_\t_\n1.1\n\n",
+            "1\tDe\t_\t_\t_\t_\t_\t_\t_\t_\n1.1\tis\t_\t_\t_\tx\t_\t_\t_\t_\n\n",
+        ] {
+            let err: ExternError = read_sentences(conllu).unwrap_err().into();
+            assert_eq!(err.get_code(), ErrorCode::new(CONLLU_ERROR));
+        }
+    }
+
+    #[test]
+    fn conllu_comments_are_converted_to_metadata() {
+        let sentences =
+            read_sentences("# sent_id = s1\n# text = Test\n1\tTest\t_\t_\t_\t_\t_\t_\t_\t_\n\n")
+                .unwrap();
+        let proto_sentence = proto::Sentence::from(sentences.into_iter().next().unwrap());
+        assert_eq!(proto_sentence.id, "s1");
+        assert_eq!(
+            proto_sentence.metadata,
+            vec![proto::Comment {
+                key: "text".to_string(),
+                value: "Test".to_string()
+            }]
+        );
+    }
+
+    #[test]
+    fn repeated_and_free_text_comments_round_trip() {
+        let conllu = "# newdoc\n# sent_id = s1\n# note = first\n# checked by hand\n# note = second\n1\tTest\t_\t_\t_\t_\t_\t_\t_\t_\n\n";
+        let sentences = read_sentences(conllu).unwrap();
+        let proto_sentence = proto::Sentence::from(sentences.into_iter().next().unwrap());
+        assert_eq!(proto_sentence.id, "s1");
+        assert_eq!(proto_sentence.id_position, 1);
+        assert_eq!(
+            proto_sentence.metadata,
+            vec![
+                proto::Comment {
+                    key: String::new(),
+                    value: "newdoc".to_string()
+                },
+                proto::Comment {
+                    key: "note".to_string(),
+                    value: "first".to_string()
+                },
+                proto::Comment {
+                    key: String::new(),
+                    value: "checked by hand".to_string()
+                },
+                proto::Comment {
+                    key: "note".to_string(),
+                    value: "second".to_string()
+                },
+            ]
+        );
+
+        // The order of the comments, including the identifier, is preserved.
+        let sentence = ExtendedSentence::try_from(proto_sentence).unwrap();
+        assert_eq!(write_sentences(&[sentence]).unwrap(), conllu);
+    }
+}
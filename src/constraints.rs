@@ -0,0 +1,228 @@
+//! Decoding constraints and annotations that are kept from the input.
+
+use std::collections::{HashMap, HashSet};
+
+use ndarray::ArrayViewMut2;
+use syntaxdot::encoders::EncoderType;
+use syntaxdot_encoders::layer::Layer;
+use udgraph::graph::{DepTriple, Sentence};
+use udgraph::token::{Token, Tokens};
+
+use crate::options::{AnnotateOptions, AnnotationPolicy};
+use crate::tagger::{HEAD, RELATION};
+
+/// Decoding constraints of a sentence.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SentenceConstraints {
+    /// The labels that may be assigned to each token, per layer.
+    ///
+    /// Tokens without an entry and layers without labels are not
+    /// constrained.
+    pub allowed_labels: Vec<HashMap<String, HashSet<String>>>,
+}
+
+impl SentenceConstraints {
+    /// Get the labels that may be assigned to a token in a layer.
+    pub fn allowed_labels(&self, token: usize, layer: &str) -> Option<&HashSet<String>> {
+        self.allowed_labels
+            .get(token)
+            .and_then(|token_labels| token_labels.get(layer))
+    }
+
+    /// Check whether any token of a layer is constrained.
+    pub(crate) fn constrains_layer(&self, layer: &str) -> bool {
+        self.allowed_labels
+            .iter()
+            .any(|token_labels| token_labels.contains_key(layer))
+    }
+}
+
+/// Get the annotation policy of the biaffine parser.
+///
+/// The head and relation are assigned together, so a policy of either
+/// layer applies to both. The parser is enabled when either layer is
+/// enabled.
+pub(crate) fn dependency_policy(options: &AnnotateOptions) -> AnnotationPolicy {
+    if !options.layer_enabled(HEAD) && !options.layer_enabled(RELATION) {
+        return AnnotationPolicy::Skip;
+    }
+
+    options
+        .policies
+        .get(HEAD)
+        .or_else(|| options.policies.get(RELATION))
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Penalize all heads of tokens with a dependency relation, except for
+/// their current head.
+///
+/// `head_scores` is a square matrix of head scores, where row `i`
+/// contains the scores of the candidate heads of token `i`. The penalty
+/// is larger than the difference between the scores of any two trees,
+/// so that the maximum spanning tree contains the relations of the
+/// sentence when they do not form a cycle. Penalized scores stay finite,
+/// since the decoder cannot handle infinite scores.
+pub(crate) fn fix_present_arcs(sentence: &Sentence, mut head_scores: ArrayViewMut2<f32>) {
+    if head_scores.is_empty() {
+        return;
+    }
+
+    let (min_score, max_score) = head_scores
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &score| {
+            (min.min(score), max.max(score))
+        });
+    let penalty = head_scores.nrows() as f32 * (max_score - min_score + 1.);
+
+    let dep_graph = sentence.dep_graph();
+    for (dependent, mut dependent_scores) in head_scores.outer_iter_mut().enumerate().skip(1) {
+        let head = match dep_graph.head(dependent) {
+            Some(triple) => triple.head(),
+            None => continue,
+        };
+
+        for (candidate, score) in dependent_scores.iter_mut().enumerate() {
+            if candidate != head {
+                *score = min_score - penalty;
+            }
+        }
+    }
+}
+
+/// Restore the dependency relations of `original` in `sentence`.
+///
+/// Returns for every token whether its relation was restored.
+pub(crate) fn keep_present_dependencies(original: &Sentence, sentence: &mut Sentence) -> Vec<bool> {
+    let original_graph = original.dep_graph();
+    let mut dep_graph = sentence.dep_graph_mut();
+
+    (1..original.len())
+        .map(|dependent| match original_graph.head(dependent) {
+            Some(triple) => {
+                dep_graph.add_deprel(DepTriple::new(
+                    triple.head(),
+                    triple.relation().map(ToOwned::to_owned),
+                    dependent,
+                ));
+                true
+            }
+            None => false,
+        })
+        .collect()
+}
+
+/// Restore the values of an encoder that are present in `original`.
+///
+/// Returns for every token whether its value was restored.
+pub(crate) fn keep_present_values(
+    encoder_type: &EncoderType,
+    original: &Sentence,
+    sentence: &mut Sentence,
+) -> Vec<bool> {
+    match encoder_type {
+        EncoderType::Dependency { .. } => keep_present_dependencies(original, sentence),
+        EncoderType::Lemma(_) | EncoderType::TdzLemma(_) => original
+            .tokens()
+            .zip(sentence.tokens_mut())
+            .map(|(original, token)| match original.lemma() {
+                Some(lemma) => {
+                    token.set_lemma(Some(lemma));
+                    true
+                }
+                None => false,
+            })
+            .collect(),
+        EncoderType::Sequence(layer) => original
+            .tokens()
+            .zip(sentence.tokens_mut())
+            .map(|(original, token)| keep_present_value(layer, original, token))
+            .collect(),
+    }
+}
+
+/// Restore the value of a layer that is present in `original`.
+///
+/// Default values of features are not considered to be present.
+fn keep_present_value(layer: &Layer, original: &Token, token: &mut Token) -> bool {
+    match layer {
+        Layer::UPos => match original.upos() {
+            Some(upos) => {
+                token.set_upos(Some(upos));
+                true
+            }
+            None => false,
+        },
+        Layer::XPos => match original.xpos() {
+            Some(xpos) => {
+                token.set_xpos(Some(xpos));
+                true
+            }
+            None => false,
+        },
+        Layer::Feature { feature, .. } => match original.features().get(feature) {
+            Some(value) => {
+                token.features_mut().insert(feature.clone(), value.clone());
+                true
+            }
+            None => false,
+        },
+        Layer::FeatureString if !original.features().is_empty() => {
+            token.set_features(original.features().clone());
+            true
+        }
+        Layer::FeatureString => false,
+        Layer::Misc { feature, .. } => match original.misc().get(feature) {
+            Some(Some(value)) => {
+                token
+                    .misc_mut()
+                    .insert(feature.clone(), Some(value.clone()));
+                true
+            }
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use ndarray::array;
+    use udgraph::graph::{DepTriple, Sentence};
+    use udgraph::token::Token;
+
+    use super::fix_present_arcs;
+
+    #[test]
+    fn present_arcs_are_fixed() {
+        let mut sentence =
+            Sentence::from_iter(vec![Token::new("Dit"), Token::new("is"), Token::new("een")]);
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(3, Some("det"), 1));
+
+        let mut head_scores = array![
+            [0.0, 0.0, 0.0, 0.0],
+            [1.0, 0.0, 4.0, 2.0],
+            [3.0, 1.0, 0.0, 2.0],
+            [1.0, 2.0, 3.0, 0.0]
+        ];
+        let check = head_scores.clone();
+        fix_present_arcs(&sentence, head_scores.view_mut());
+
+        // Tokens without a relation keep their scores.
+        assert_eq!(head_scores.row(0), check.row(0));
+        assert_eq!(head_scores.row(2), check.row(2));
+        assert_eq!(head_scores.row(3), check.row(3));
+
+        // The other heads of the first token are penalized, such that any
+        // tree with the fixed arc scores higher than any tree without it.
+        assert_eq!(head_scores[[1, 3]], 2.0);
+        for &head in &[0, 1, 2] {
+            assert!(head_scores[[1, head]] + 3. * 4. < head_scores[[1, 3]]);
+            assert!(head_scores[[1, head]].is_finite());
+        }
+    }
+}
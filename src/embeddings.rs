@@ -0,0 +1,131 @@
+//! Token and sentence embeddings.
+
+use ffi_support::implement_into_ffi_by_protobuf;
+use ndarray::{s, Array1, Array2, ArrayView2, Axis};
+
+use crate::options::SentencePooling;
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/syntaxdot.embeddings.rs"));
+}
+
+impl From<Vec<Array2<f32>>> for proto::Embeddings {
+    /// Convert the token embedding matrices of sentences.
+    ///
+    /// Every matrix has shape `[n_tokens, dims]`.
+    fn from(sentence_embeddings: Vec<Array2<f32>>) -> Self {
+        let mut embeddings = proto::Embeddings::default();
+
+        for (sentence_idx, sentence_embeddings) in sentence_embeddings.into_iter().enumerate() {
+            embeddings.dims = sentence_embeddings.ncols() as u32;
+
+            for token_idx in 0..sentence_embeddings.nrows() {
+                embeddings.sentence_indices.push(sentence_idx as u32);
+                embeddings.token_indices.push(token_idx as u32);
+            }
+
+            embeddings.data.extend(sentence_embeddings.iter());
+        }
+
+        embeddings
+    }
+}
+
+impl From<Array2<f32>> for proto::SentenceEmbeddings {
+    fn from(embeddings: Array2<f32>) -> Self {
+        proto::SentenceEmbeddings {
+            n_sentences: embeddings.nrows() as u32,
+            dims: embeddings.ncols() as u32,
+            data: embeddings.iter().copied().collect(),
+        }
+    }
+}
+
+/// Pool the embeddings of sentences.
+///
+/// The first row of the embedding matrix of a sentence must be the
+/// embedding of the root, followed by the token embeddings. Returns a
+/// matrix with shape `[n_sentences, dims]`.
+pub fn pool_sentences(
+    sentence_embeddings: &[Array2<f32>],
+    pooling: SentencePooling,
+) -> Array2<f32> {
+    let dims = sentence_embeddings.first().map(Array2::ncols).unwrap_or(0);
+
+    let mut pooled = Array2::zeros((sentence_embeddings.len(), dims));
+    for (mut sentence_pooled, embeddings) in pooled.outer_iter_mut().zip(sentence_embeddings) {
+        sentence_pooled.assign(&pool_sentence(embeddings.view(), pooling));
+    }
+
+    pooled
+}
+
+fn pool_sentence(embeddings: ArrayView2<f32>, pooling: SentencePooling) -> Array1<f32> {
+    let tokens = embeddings.slice(s![1.., ..]);
+
+    match pooling {
+        SentencePooling::Cls => embeddings.row(0).to_owned(),
+        SentencePooling::Max if tokens.nrows() > 0 => {
+            tokens.fold_axis(Axis(0), f32::NEG_INFINITY, |&max, &v| max.max(v))
+        }
+        SentencePooling::Mean if tokens.nrows() > 0 => tokens
+            .mean_axis(Axis(0))
+            .expect("Cannot compute mean of tokens"),
+        // Sentences without tokens have zero vectors.
+        _ => Array1::zeros(embeddings.ncols()),
+    }
+}
+
+implement_into_ffi_by_protobuf!(proto::Embeddings);
+implement_into_ffi_by_protobuf!(proto::SentenceEmbeddings);
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+
+    use super::{pool_sentences, proto};
+    use crate::options::SentencePooling;
+
+    #[test]
+    fn embeddings_are_converted_to_protobuf() {
+        let embeddings =
+            proto::Embeddings::from(vec![array![[1., 2.], [3., 4.]], array![[5., 6.]]]);
+        assert_eq!(
+            embeddings,
+            proto::Embeddings {
+                dims: 2,
+                sentence_indices: vec![0, 0, 1],
+                token_indices: vec![0, 1, 0],
+                data: vec![1., 2., 3., 4., 5., 6.],
+            }
+        );
+    }
+
+    #[test]
+    fn sentence_embeddings_are_pooled() {
+        // The first row is the root.
+        let embeddings = vec![array![[1., 1.], [2., -1.], [4., 3.]], array![[5., 6.]]];
+
+        assert_eq!(
+            pool_sentences(&embeddings, SentencePooling::Mean),
+            array![[3., 1.], [0., 0.]]
+        );
+        assert_eq!(
+            pool_sentences(&embeddings, SentencePooling::Max),
+            array![[4., 3.], [0., 0.]]
+        );
+        assert_eq!(
+            pool_sentences(&embeddings, SentencePooling::Cls),
+            array![[1., 1.], [5., 6.]]
+        );
+
+        assert_eq!(
+            proto::SentenceEmbeddings::from(pool_sentences(&embeddings, SentencePooling::Cls)),
+            proto::SentenceEmbeddings {
+                n_sentences: 2,
+                dims: 2,
+                data: vec![1., 1., 5., 6.],
+            }
+        );
+    }
+}
@@ -12,16 +12,60 @@ pub mod error_codes {
     pub const LOAD_PARAMETERS_ERROR: i32 = 4;
     pub const SYNTAXDOT_ERROR: i32 = 5;
     pub const DECODE_PROTOBUF_ERROR: i32 = 6;
+    pub const INVALID_DEVICE_ERROR: i32 = 7;
+    pub const INVALID_OPTION_ERROR: i32 = 8;
+    pub const UNKNOWN_ENCODER_ERROR: i32 = 9;
+    pub const SEQUENCE_TOO_LONG_ERROR: i32 = 10;
+    pub const ARCHIVE_ERROR: i32 = 11;
+    pub const ARCHIVE_MISSING_MEMBER_ERROR: i32 = 12;
+    pub const ARCHIVE_CORRUPT_MEMBER_ERROR: i32 = 13;
+    pub const CONLLU_ERROR: i32 = 14;
+    pub const JSON_ERROR: i32 = 15;
+    pub const INVALID_CONSTRAINT_ERROR: i32 = 16;
+    pub const UNSUPPORTED_ERROR: i32 = 17;
+    pub const SESSION_FULL_ERROR: i32 = 18;
+    pub const QUEUE_FULL_ERROR: i32 = 19;
+    pub const WORKERS_STOPPED_ERROR: i32 = 20;
 }
 
 #[derive(Debug, Error)]
 pub enum AnnotatorError {
+    #[error("Cannot read model archive: {0}")]
+    Archive(String),
+
+    #[error("Model archive member `{0}` is corrupt: {1}")]
+    ArchiveCorruptMember(String, String),
+
+    #[error("Model archive member `{0}` is missing")]
+    ArchiveMissingMember(String),
+
+    #[error("Cannot read or write CoNLL-U: {0}")]
+    Conllu(#[from] conllu::IOError),
+
+    #[error("Cannot read CoNLL-U: {0}")]
+    ConlluParse(String),
+
     #[error("Cannot construct BERT model: {0}")]
     Transformer(#[from] TransformerError),
 
+    #[error("Invalid constraint: {0}")]
+    InvalidConstraint(String),
+
+    #[error("Invalid device: {0}")]
+    InvalidDevice(String),
+
+    #[error("Invalid labels in `{0}`: {1}")]
+    InvalidLabels(String, String),
+
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
     #[error("{0}: {1}")]
     Io(String, io::Error),
 
+    #[error("Cannot read or write JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("Cannot deserialize encoders from `{0}`: {1}")]
     LoadEncoders(String, serde_yaml::Error),
 
@@ -31,20 +75,54 @@ pub enum AnnotatorError {
     #[error("Cannot decode protobuf: {0}")]
     ProtobufDecode(#[from] prost::DecodeError),
 
+    #[error("Queue is full, at most {0} asynchronous calls wait for a worker")]
+    QueueFull(usize),
+
+    #[error("Sentence has {0} pieces, the maximum sequence length is {1}")]
+    SequenceTooLong(usize, usize),
+
+    #[error("Session is full, it buffers at most {0} sentences until they are polled")]
+    SessionFull(usize),
+
     #[error(transparent)]
     SyntaxDot(#[from] SyntaxDotError),
+
+    #[error("Unknown encoder: {0}")]
+    UnknownEncoder(String),
+
+    #[error("Unsupported: {0}")]
+    Unsupported(String),
+
+    #[error("Worker threads have stopped")]
+    WorkersStopped,
 }
 
 impl From<&AnnotatorError> for ErrorCode {
     fn from(err: &AnnotatorError) -> Self {
         use AnnotatorError::*;
         match err {
+            Archive(_) => ErrorCode::new(error_codes::ARCHIVE_ERROR),
+            ArchiveCorruptMember(_, _) => ErrorCode::new(error_codes::ARCHIVE_CORRUPT_MEMBER_ERROR),
+            ArchiveMissingMember(_) => ErrorCode::new(error_codes::ARCHIVE_MISSING_MEMBER_ERROR),
+            Conllu(_) => ErrorCode::new(error_codes::CONLLU_ERROR),
+            ConlluParse(_) => ErrorCode::new(error_codes::CONLLU_ERROR),
             Transformer(_) => ErrorCode::new(error_codes::TRANSFORMER_ERROR),
+            InvalidConstraint(_) => ErrorCode::new(error_codes::INVALID_CONSTRAINT_ERROR),
+            InvalidDevice(_) => ErrorCode::new(error_codes::INVALID_DEVICE_ERROR),
+            InvalidLabels(_, _) => ErrorCode::new(error_codes::LOAD_ENCODERS_ERROR),
+            InvalidOption(_) => ErrorCode::new(error_codes::INVALID_OPTION_ERROR),
             Io(_, _) => ErrorCode::new(error_codes::IO_ERROR),
+            Json(_) => ErrorCode::new(error_codes::JSON_ERROR),
             LoadEncoders(_, _) => ErrorCode::new(error_codes::LOAD_ENCODERS_ERROR),
             LoadParameters(_) => ErrorCode::new(error_codes::LOAD_PARAMETERS_ERROR),
             ProtobufDecode(_) => ErrorCode::new(error_codes::DECODE_PROTOBUF_ERROR),
+            QueueFull(_) => ErrorCode::new(error_codes::QUEUE_FULL_ERROR),
+            SequenceTooLong(_, _) => ErrorCode::new(error_codes::SEQUENCE_TOO_LONG_ERROR),
+            SessionFull(_) => ErrorCode::new(error_codes::SESSION_FULL_ERROR),
             SyntaxDot(_) => ErrorCode::new(error_codes::SYNTAXDOT_ERROR),
+            UnknownEncoder(_) => ErrorCode::new(error_codes::UNKNOWN_ENCODER_ERROR),
+            Unsupported(_) => ErrorCode::new(error_codes::UNSUPPORTED_ERROR),
+            WorkersStopped => ErrorCode::new(error_codes::WORKERS_STOPPED_ERROR),
         }
     }
 }
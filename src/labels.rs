@@ -0,0 +1,606 @@
+//! Label inventories of serialized encoders.
+//!
+//! The label inventories of syntaxdot encoders are not public. So, they
+//! are read from the serialized encoders, which store the labels in the
+//! order of their encodings. The inventories are used to decode the
+//! candidate labels of a token without decoding the whole sentence.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use syntaxdot::encoders::Encoders;
+use syntaxdot_encoders::depseq::{DependencyEncoding, POSLayer};
+use syntaxdot_encoders::lemma::{BackoffStrategy, EditTree};
+use syntaxdot_encoders::EncodingProb;
+use udgraph::graph::{Node, Sentence};
+use udgraph::token::Tokens;
+
+use crate::AnnotatorError;
+
+/// The number of labels that the model reserves.
+///
+/// The first two classes of the model are used for padding and
+/// continuation pieces, so label inventories start at this number.
+pub const N_RESERVED_LABELS: usize = 2;
+
+/// Part-of-speech of the root in relative part-of-speech encodings.
+const ROOT_POS: &str = "ROOT";
+
+/// A serialized numberer.
+#[derive(Deserialize)]
+pub struct SerializedNumberer<V> {
+    /// The values, in the order of their numbers.
+    pub values: Vec<V>,
+
+    /// The number of the first value.
+    pub start_at: usize,
+}
+
+impl<V> SerializedNumberer<V> {
+    /// The number of values, including the reserved numbers.
+    fn len(&self) -> usize {
+        self.start_at + self.values.len()
+    }
+
+    /// Get the value of a number.
+    fn value(&self, number: usize) -> Option<&V> {
+        self.values.get(number.checked_sub(self.start_at)?)
+    }
+}
+
+/// A serialized categorical encoder.
+#[derive(Deserialize)]
+struct SerializedCategoricalEncoder<E, V> {
+    inner: E,
+    numberer: SerializedNumberer<V>,
+}
+
+/// A serialized edit tree encoder.
+#[derive(Deserialize)]
+struct SerializedEditTreeEncoder {
+    backoff_strategy: BackoffStrategy,
+}
+
+/// A serialized relative part-of-speech encoder.
+#[derive(Deserialize)]
+struct SerializedRelativePosEncoder {
+    pos_layer: POSLayer,
+}
+
+/// A relative head position by part-of-speech.
+#[derive(Deserialize)]
+pub struct RelativePosHead {
+    pos: String,
+    position: isize,
+}
+
+/// A serialized encoder.
+#[derive(Deserialize)]
+enum SerializedEncoder {
+    Lemma(SerializedCategoricalEncoder<SerializedEditTreeEncoder, EditTree>),
+    Layer(SerializedCategoricalEncoder<serde::de::IgnoredAny, String>),
+    #[serde(rename = "RelativePOS")]
+    RelativePos(
+        SerializedCategoricalEncoder<
+            SerializedRelativePosEncoder,
+            DependencyEncoding<RelativePosHead>,
+        >,
+    ),
+    RelativePosition(
+        SerializedCategoricalEncoder<serde::de::IgnoredAny, DependencyEncoding<isize>>,
+    ),
+    TdzLemma(SerializedCategoricalEncoder<SerializedEditTreeEncoder, EditTree>),
+}
+
+/// A serialized encoder with its name.
+#[derive(Deserialize)]
+struct SerializedNamedEncoder {
+    encoder: SerializedEncoder,
+    name: String,
+}
+
+/// The label inventory of a sequence labeling encoder.
+pub enum EncoderLabels {
+    /// Labels of a sequence labeling layer.
+    Layer(SerializedNumberer<String>),
+
+    /// Edit trees of a lemmatizer.
+    Lemma {
+        backoff_strategy: BackoffStrategy,
+        edit_trees: SerializedNumberer<EditTree>,
+    },
+
+    /// Dependency relations with relative head positions by part-of-speech.
+    RelativePos {
+        pos_layer: POSLayer,
+        encodings: SerializedNumberer<DependencyEncoding<RelativePosHead>>,
+    },
+
+    /// Dependency relations with relative head positions.
+    RelativePosition(SerializedNumberer<DependencyEncoding<isize>>),
+}
+
+impl EncoderLabels {
+    /// The number of the first label.
+    fn start_at(&self) -> usize {
+        match self {
+            EncoderLabels::Layer(labels) => labels.start_at,
+            EncoderLabels::Lemma { edit_trees, .. } => edit_trees.start_at,
+            EncoderLabels::RelativePos { encodings, .. } => encodings.start_at,
+            EncoderLabels::RelativePosition(encodings) => encodings.start_at,
+        }
+    }
+
+    /// The number of encodings, including reserved encodings.
+    fn len(&self) -> usize {
+        match self {
+            EncoderLabels::Layer(labels) => labels.len(),
+            EncoderLabels::Lemma { edit_trees, .. } => edit_trees.len(),
+            EncoderLabels::RelativePos { encodings, .. } => encodings.len(),
+            EncoderLabels::RelativePosition(encodings) => encodings.len(),
+        }
+    }
+
+    /// Get the labels of a sequence labeling layer with their encodings.
+    ///
+    /// Returns `None` if the encoder does not encode a sequence
+    /// labeling layer.
+    pub fn layer_labels(&self) -> Option<HashMap<String, usize>> {
+        match self {
+            EncoderLabels::Layer(labels) => Some(
+                labels
+                    .values
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, label)| (label.clone(), idx + labels.start_at))
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Get the values of the candidate labels of every token.
+    ///
+    /// The values are in the format that is used for scores: dependency
+    /// relations are formatted as `head:relation`, the other layers use
+    /// their value. Candidates are decoded per token, so they do not
+    /// depend on the candidates of other tokens. `None` is used for
+    /// candidates that cannot be applied to the token, e.g. a head
+    /// position that is outside the sentence or an edit tree that does
+    /// not match the form.
+    pub fn candidate_values(
+        &self,
+        sentence: &Sentence,
+        label_probs: &[Vec<EncodingProb<usize>>],
+    ) -> Vec<Vec<Option<String>>> {
+        match self {
+            EncoderLabels::Layer(labels) => {
+                map_candidates(label_probs, |_, label| labels.value(label).cloned())
+            }
+            EncoderLabels::Lemma {
+                backoff_strategy,
+                edit_trees,
+            } => {
+                let forms = sentence
+                    .tokens()
+                    .map(|token| token.form().chars().collect::<Vec<_>>())
+                    .collect::<Vec<_>>();
+                map_candidates(label_probs, |token_idx, label| {
+                    let form = &forms[token_idx];
+                    match edit_trees.value(label)?.apply(form) {
+                        Some(lemma) => Some(lemma.into_iter().collect()),
+                        None if *backoff_strategy == BackoffStrategy::Form => {
+                            Some(form.iter().collect())
+                        }
+                        None => None,
+                    }
+                })
+            }
+            EncoderLabels::RelativePos {
+                pos_layer,
+                encodings,
+            } => {
+                let pos_table = pos_position_table(sentence, *pos_layer);
+                map_candidates(label_probs, |token_idx, label| {
+                    let encoding = encodings.value(label)?;
+                    let RelativePosHead { pos, position } = encoding.head();
+                    let head =
+                        relative_pos_head(pos_table.get(pos.as_str())?, token_idx + 1, *position)?;
+                    Some(format!("{}:{}", head, encoding.label()))
+                })
+            }
+            EncoderLabels::RelativePosition(encodings) => {
+                map_candidates(label_probs, |token_idx, label| {
+                    let encoding = encodings.value(label)?;
+                    let head = (token_idx + 1) as isize + encoding.head();
+                    if head < 0 || head >= sentence.len() as isize {
+                        return None;
+                    }
+                    Some(format!("{}:{}", head, encoding.label()))
+                })
+            }
+        }
+    }
+}
+
+/// Map the candidate labels of every token to values.
+///
+/// `f` is called with the token index and the label.
+fn map_candidates<F>(label_probs: &[Vec<EncodingProb<usize>>], f: F) -> Vec<Vec<Option<String>>>
+where
+    F: Fn(usize, usize) -> Option<String>,
+{
+    label_probs
+        .iter()
+        .enumerate()
+        .map(|(token_idx, token_label_probs)| {
+            token_label_probs
+                .iter()
+                .map(|label_prob| f(token_idx, *label_prob.encoding()))
+                .collect()
+        })
+        .collect()
+}
+
+/// Get the sentence positions of each part-of-speech.
+fn pos_position_table(sentence: &Sentence, pos_layer: POSLayer) -> HashMap<&str, Vec<usize>> {
+    let mut table: HashMap<&str, Vec<usize>> = HashMap::new();
+
+    for (idx, node) in sentence.iter().enumerate() {
+        let pos = match node {
+            Node::Root => ROOT_POS,
+            Node::Token(token) => {
+                let pos = match pos_layer {
+                    POSLayer::UPos => token.upos(),
+                    POSLayer::XPos => token.xpos(),
+                };
+
+                match pos {
+                    Some(pos) => pos,
+                    None => continue,
+                }
+            }
+        };
+
+        table.entry(pos).or_default().push(idx);
+    }
+
+    table
+}
+
+/// Get the head of a dependent from a relative position by part-of-speech.
+///
+/// `indices` are the sorted sentence positions of the part-of-speech of
+/// the head. Returns `None` when the head is out of bounds. This follows
+/// the decoder of `RelativePOSEncoder`: when the dependent does not have
+/// the part-of-speech of the head, the first succeeding position is +1.
+fn relative_pos_head(indices: &[usize], dependent: usize, position: isize) -> Option<usize> {
+    let (dependent_position, position) = match indices.binary_search(&dependent) {
+        Ok(idx) => (idx, position),
+        Err(idx) if position > 0 => (idx, position - 1),
+        Err(idx) => (idx, position),
+    };
+
+    let head_position = dependent_position as isize + position;
+    if head_position < 0 {
+        return None;
+    }
+
+    indices.get(head_position as usize).copied()
+}
+
+/// Read the label inventories of sequence labeling encoders.
+///
+/// Returns a mapping from encoder names to their label inventories.
+/// `loaded` are the encoders that were deserialized from the same
+/// data, their number of labels must match the inventory. The labels
+/// of every inventory must start after the labels that the model
+/// reserves.
+pub fn read_encoder_labels(
+    serialized: &serde_yaml::Value,
+    name: &str,
+    loaded: &Encoders,
+) -> Result<HashMap<String, EncoderLabels>, AnnotatorError> {
+    let serialized: Vec<SerializedNamedEncoder> = serde_yaml::from_value(serialized.clone())
+        .map_err(|err| AnnotatorError::LoadEncoders(name.to_string(), err))?;
+
+    if serialized.len() != loaded.len() {
+        return Err(AnnotatorError::InvalidLabels(
+            name.to_string(),
+            "cannot read the labels of all encoders".to_string(),
+        ));
+    }
+
+    let mut encoder_labels = HashMap::new();
+    for (encoder, loaded) in serialized.into_iter().zip(loaded.iter()) {
+        let labels = match encoder.encoder {
+            SerializedEncoder::Layer(encoder) => EncoderLabels::Layer(encoder.numberer),
+            SerializedEncoder::Lemma(encoder) | SerializedEncoder::TdzLemma(encoder) => {
+                EncoderLabels::Lemma {
+                    backoff_strategy: encoder.inner.backoff_strategy,
+                    edit_trees: encoder.numberer,
+                }
+            }
+            SerializedEncoder::RelativePos(encoder) => EncoderLabels::RelativePos {
+                pos_layer: encoder.inner.pos_layer,
+                encodings: encoder.numberer,
+            },
+            SerializedEncoder::RelativePosition(encoder) => {
+                EncoderLabels::RelativePosition(encoder.numberer)
+            }
+        };
+
+        if encoder.name != loaded.name() || labels.len() != loaded.encoder().len() {
+            return Err(AnnotatorError::InvalidLabels(
+                name.to_string(),
+                format!("cannot read the labels of encoder {}", encoder.name),
+            ));
+        }
+
+        if labels.start_at() != N_RESERVED_LABELS {
+            return Err(AnnotatorError::InvalidLabels(
+                name.to_string(),
+                format!(
+                    "the labels of encoder {} start at {}, expected {}",
+                    encoder.name,
+                    labels.start_at(),
+                    N_RESERVED_LABELS
+                ),
+            ));
+        }
+
+        encoder_labels.insert(encoder.name, labels);
+    }
+
+    Ok(encoder_labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use syntaxdot::encoders::{
+        DependencyEncoder, EncoderType, Encoders, EncodersConfig, NamedEncoderConfig,
+    };
+    use syntaxdot_encoders::depseq::{DependencyEncoding, POSLayer};
+    use syntaxdot_encoders::layer::Layer;
+    use syntaxdot_encoders::lemma::{BackoffStrategy, EditTree};
+    use syntaxdot_encoders::{EncodingProb, SentenceEncoder};
+    use udgraph::graph::{DepTriple, Sentence};
+    use udgraph::token::TokenBuilder;
+
+    use super::{read_encoder_labels, EncoderLabels, SerializedNumberer};
+    use crate::AnnotatorError;
+
+    const LABELS: &str = r#"
+- encoder:
+    Layer:
+      inner:
+        layer: upos
+      numberer:
+        values:
+          - NOUN
+          - VERB
+        start_at: 2
+  name: upos
+- encoder:
+    RelativePOS:
+      inner:
+        pos_layer: upos
+        root_relation: root
+      numberer:
+        values:
+          - head:
+              pos: VERB
+              position: 1
+            label: nsubj
+          - head:
+              pos: ROOT
+              position: -1
+            label: root
+        start_at: 2
+  name: deprel
+"#;
+
+    fn sentence() -> Sentence {
+        let mut sentence = Sentence::new();
+        sentence.push(TokenBuilder::new("Kinderen").upos("NOUN").into());
+        sentence.push(TokenBuilder::new("hadden").upos("VERB").into());
+        sentence
+    }
+
+    fn candidates(labels: &[&[usize]]) -> Vec<Vec<EncodingProb<usize>>> {
+        labels
+            .iter()
+            .map(|token_labels| {
+                token_labels
+                    .iter()
+                    .map(|&label| EncodingProb::new(label, 0.5))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn labels_are_read_from_serialized_encoders() {
+        let serialized: serde_yaml::Value = serde_yaml::from_str(LABELS).unwrap();
+        let encoders = serde_yaml::from_value(serialized.clone()).unwrap();
+        let labels = read_encoder_labels(&serialized, "labels", &encoders).unwrap();
+
+        let upos = labels["upos"].layer_labels().unwrap();
+        assert_eq!(upos.len(), 2);
+        assert_eq!(upos["NOUN"], 2);
+        assert_eq!(upos["VERB"], 3);
+        assert!(labels["deprel"].layer_labels().is_none());
+    }
+
+    #[test]
+    fn labels_must_match_the_encoders() {
+        let serialized: serde_yaml::Value = serde_yaml::from_str(LABELS).unwrap();
+        let encoders = serde_yaml::from_value(serialized).unwrap();
+
+        let truncated = LABELS.replace("          - VERB\n", "");
+        let serialized: serde_yaml::Value = serde_yaml::from_str(&truncated).unwrap();
+        assert!(matches!(
+            read_encoder_labels(&serialized, "labels", &encoders),
+            Err(AnnotatorError::InvalidLabels(_, _))
+        ));
+    }
+
+    #[test]
+    fn labels_are_read_from_syntaxdot_encoders() {
+        let mut sentence = Sentence::new();
+        sentence.push(
+            TokenBuilder::new("Kinderen")
+                .upos("NOUN")
+                .lemma("kind")
+                .into(),
+        );
+        sentence.push(
+            TokenBuilder::new("hadden")
+                .upos("VERB")
+                .lemma("hebben")
+                .into(),
+        );
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+        sentence
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+
+        let named = |name: &str, encoder| NamedEncoderConfig {
+            encoder,
+            name: name.to_string(),
+        };
+        let dependency = |encoder| EncoderType::Dependency {
+            encoder,
+            root_relation: "root".to_string(),
+        };
+        let encoders = Encoders::from(&EncodersConfig(vec![
+            named("upos", EncoderType::Sequence(Layer::UPos)),
+            named("lemma", EncoderType::Lemma(BackoffStrategy::Nothing)),
+            named("tdz", EncoderType::TdzLemma(BackoffStrategy::Nothing)),
+            named(
+                "deprel",
+                dependency(DependencyEncoder::RelativePOS(POSLayer::UPos)),
+            ),
+            named("deppos", dependency(DependencyEncoder::RelativePosition)),
+        ]));
+
+        // Encoding the sentence adds its labels to the inventories.
+        let encodings = encoders
+            .iter()
+            .map(|encoder| encoder.encoder().encode(&sentence).unwrap())
+            .collect::<Vec<_>>();
+
+        let serialized = serde_yaml::to_value(&encoders).unwrap();
+        let labels = read_encoder_labels(&serialized, "labels", &encoders).unwrap();
+
+        // Decoding the encodings with the inventories gives the values
+        // of the sentence.
+        let expected = [
+            vec!["NOUN", "VERB"],
+            vec!["kind", "hebben"],
+            vec!["kind", "hebben"],
+            vec!["2:nsubj", "0:root"],
+            vec!["2:nsubj", "0:root"],
+        ];
+        for ((encoder, encoding), expected) in encoders.iter().zip(encodings).zip(&expected) {
+            let label_probs = encoding
+                .into_iter()
+                .map(|label| vec![EncodingProb::new(label, 1.)])
+                .collect::<Vec<_>>();
+            assert_eq!(
+                labels[encoder.name()].candidate_values(&sentence, &label_probs),
+                expected
+                    .iter()
+                    .map(|&value| vec![Some(value.to_string())])
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn labels_must_start_after_reserved_labels() {
+        let shifted = LABELS.replacen("start_at: 2", "start_at: 1", 1);
+        let serialized: serde_yaml::Value = serde_yaml::from_str(&shifted).unwrap();
+        let encoders = serde_yaml::from_value(serialized.clone()).unwrap();
+        assert!(matches!(
+            read_encoder_labels(&serialized, "labels", &encoders),
+            Err(AnnotatorError::InvalidLabels(_, _))
+        ));
+    }
+
+    #[test]
+    fn layer_candidates_are_labels() {
+        let serialized: serde_yaml::Value = serde_yaml::from_str(LABELS).unwrap();
+        let encoders = serde_yaml::from_value(serialized.clone()).unwrap();
+        let labels = read_encoder_labels(&serialized, "labels", &encoders).unwrap();
+
+        assert_eq!(
+            labels["upos"].candidate_values(&sentence(), &candidates(&[&[2, 3], &[3]])),
+            vec![
+                vec![Some("NOUN".to_string()), Some("VERB".to_string())],
+                vec![Some("VERB".to_string())]
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_pos_candidates_resolve_heads_per_token() {
+        let serialized: serde_yaml::Value = serde_yaml::from_str(LABELS).unwrap();
+        let encoders = serde_yaml::from_value(serialized.clone()).unwrap();
+        let labels = read_encoder_labels(&serialized, "labels", &encoders).unwrap();
+
+        // The second token has no succeeding verb.
+        assert_eq!(
+            labels["deprel"].candidate_values(&sentence(), &candidates(&[&[2, 3], &[2, 3]])),
+            vec![
+                vec![Some("2:nsubj".to_string()), Some("0:root".to_string())],
+                vec![None, Some("0:root".to_string())]
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_position_candidates_must_be_in_the_sentence() {
+        let labels = EncoderLabels::RelativePosition(SerializedNumberer {
+            values: vec![
+                DependencyEncoding::new(1, "nsubj"),
+                DependencyEncoding::new(-2, "root"),
+            ],
+            start_at: 2,
+        });
+
+        assert_eq!(
+            labels.candidate_values(&sentence(), &candidates(&[&[2, 3], &[2, 3]])),
+            vec![
+                vec![Some("2:nsubj".to_string()), None],
+                vec![None, Some("0:root".to_string())]
+            ]
+        );
+    }
+
+    #[test]
+    fn lemma_candidates_apply_edit_trees_to_the_form() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        let edit_trees = SerializedNumberer {
+            values: vec![
+                EditTree::create_tree(&chars("Kinderen"), &chars("kind")).unwrap(),
+                EditTree::create_tree(&chars("hadden"), &chars("hebben")).unwrap(),
+            ],
+            start_at: 2,
+        };
+        let labels = EncoderLabels::Lemma {
+            backoff_strategy: BackoffStrategy::Nothing,
+            edit_trees,
+        };
+
+        assert_eq!(
+            labels.candidate_values(&sentence(), &candidates(&[&[2, 3], &[3, 2]])),
+            vec![
+                vec![Some("kind".to_string()), None],
+                vec![Some("hebben".to_string()), None]
+            ]
+        );
+    }
+}
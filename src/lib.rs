@@ -1,29 +1,83 @@
+use std::convert::{TryFrom, TryInto};
+use std::mem;
+use std::os::raw::c_void;
+use std::sync::{Mutex, PoisonError};
+
 use ffi_support::{
     define_bytebuffer_destructor, define_handle_map_deleter, define_string_destructor, ByteBuffer,
     ConcurrentHandleMap, ExternError, FfiStr,
 };
 use lazy_static::lazy_static;
-use tch::Device;
+use udgraph::graph::Sentence;
 
 mod annotator;
 use annotator::Annotator;
 
+mod archive;
+
+mod conll;
+
+mod constraints;
+
+pub mod embeddings;
+
 mod error;
 use error::AnnotatorError;
 use std::ffi::CString;
 use std::os::raw::c_char;
 
+mod labels;
+
+pub mod model;
+
+pub mod options;
+use options::{AnnotateOptions, AnnotatorOptions, EmbedOptions};
+
+pub mod pieces;
+
+pub mod segmenter;
+use segmenter::Segmenter;
+
 pub mod sentences;
+use sentences::ExtendedSentence;
+
+mod memfile;
+
+mod session;
+use session::Session;
+
+mod tagger;
+use tagger::SentenceScores;
 
 mod util;
 
+mod windows;
+
+mod worker;
+use worker::WorkerPool;
+
+/// The maximum number of asynchronous calls that wait for a worker.
+const MAX_QUEUED_CALLS: usize = 64;
+
 lazy_static! {
     static ref ANNOTATORS: ConcurrentHandleMap<Annotator> = ConcurrentHandleMap::new();
+    static ref SESSIONS: ConcurrentHandleMap<Session> = ConcurrentHandleMap::new();
+    /// Serializes loading annotators, so that no annotator is loaded
+    /// between checking and applying thread settings.
+    static ref LOAD_LOCK: Mutex<()> = Mutex::new(());
+    /// The number of worker threads for asynchronous calls, `None` once
+    /// the workers are started.
+    static ref NUM_WORKERS: Mutex<Option<usize>> = Mutex::new(Some(1));
+    static ref WORKERS: WorkerPool = WorkerPool::new(
+        NUM_WORKERS.lock().unwrap().take().unwrap_or(1),
+        MAX_QUEUED_CALLS
+    );
     static ref SYNTAXDOT_VERSION: CString = CString::new(syntaxdot::VERSION).unwrap();
 }
 
 define_bytebuffer_destructor!(syntaxdot_free_bytebuffer);
 define_handle_map_deleter!(ANNOTATORS, syntaxdot_annotator_free);
+define_handle_map_deleter!(SESSIONS, syntaxdot_session_close);
 define_string_destructor!(syntaxdot_free_string);
 
 unsafe fn get_buffer<'a>(data: *const u8, len: i32) -> &'a [u8] {
@@ -54,9 +108,261 @@ pub unsafe extern "C" fn syntaxdot_annotator_annotate(
         let buffer = get_buffer(sentences_data, sentences_data_len);
         let sentences: sentences::proto::Sentences =
             prost::Message::decode(buffer).map_err(AnnotatorError::ProtobufDecode)?;
-        let sentences: sentences::Sentences = sentences.into();
+        let options = AnnotateOptions {
+            batch_size,
+            ..Default::default()
+        };
+        annotate_proto_sentences(annotator, sentences, &options).map_err(Into::into)
+    })
+}
+
+/// Annotate the given sentences with options.
+///
+/// The options must be a serialized `AnnotateOptions` protobuf message,
+/// or an empty buffer to use the default options.
+///
+/// # Safety
+///
+/// Safe use of this function requires valid pointers `sentences_data` and
+/// `options_data` with correct lengths `sentences_data_len` and
+/// `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_annotate_with_options(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            prost::Message::decode(get_buffer(sentences_data, sentences_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: options::proto::AnnotateOptions =
+            prost::Message::decode(get_buffer(options_data, options_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: AnnotateOptions = options.try_into()?;
+        annotate_proto_sentences(annotator, sentences, &options).map_err(Into::into)
+    })
+}
+
+/// Callback that receives the result of an asynchronous call.
+///
+/// The callback owns the buffer and the error message.
+pub type AnnotateCallback =
+    extern "C" fn(user_data: *mut c_void, result: ByteBuffer, err: ExternError);
+
+/// User data of a callback, which is only passed back to the caller.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Annotate the given sentences asynchronously.
+///
+/// This function is like `syntaxdot_annotator_annotate_with_options`,
+/// but returns once the call is queued. The sentences are annotated on
+/// a worker thread, which then calls `callback` with `user_data` and the
+/// result or error. This function never blocks. If the call cannot be
+/// queued, because the queue is full or the workers have stopped,
+/// `callback` is called with the error on the calling thread before this
+/// function returns.
+///
+/// # Safety
+///
+/// Safe use of this function requires valid pointers `sentences_data` and
+/// `options_data` with correct lengths `sentences_data_len` and
+/// `options_data_len`. The buffers are copied and can be deallocated
+/// when this function returns.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_annotate_async(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    options_data: *const u8,
+    options_data_len: i32,
+    callback: AnnotateCallback,
+    user_data: *mut c_void,
+) {
+    let mut err = ExternError::success();
+    ffi_support::call_with_result(&mut err, || {
+        let sentences_data = get_buffer(sentences_data, sentences_data_len).to_vec();
+        let options_data = get_buffer(options_data, options_data_len).to_vec();
+        let user_data = UserData(user_data);
+
+        WORKERS.execute(move || {
+            let mut err = ExternError::success();
+            let buffer = syntaxdot_annotator_annotate_with_options(
+                handle,
+                sentences_data.as_ptr(),
+                sentences_data.len() as i32,
+                options_data.as_ptr(),
+                options_data.len() as i32,
+                &mut err,
+            );
+            callback(user_data.0, buffer, err);
+        })
+    });
+
+    if !err.get_code().is_success() {
+        callback(user_data, ByteBuffer::default(), err);
+    }
+}
+
+/// Annotate protobuf sentences.
+///
+/// When per-sentence errors are requested, sentences that cannot be
+/// converted are returned unchanged with their error.
+fn annotate_proto_sentences(
+    annotator: &Annotator,
+    sentences: sentences::proto::Sentences,
+    options: &AnnotateOptions,
+) -> Result<sentences::proto::Sentences, AnnotatorError> {
+    let mut invalid_sentences = Vec::new();
+    let mut valid_sentences = Vec::with_capacity(sentences.sentences.len());
+    for (idx, mut sentence) in sentences.sentences.into_iter().enumerate() {
+        match sentences::check_heads(&sentence) {
+            Ok(()) => valid_sentences.push(ExtendedSentence::try_from(sentence)?),
+            Err(err) if options.per_sentence_errors => {
+                sentence.status = Some((&err).into());
+                invalid_sentences.push((idx, sentence));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    let annotated_sentences = annotate_extended_sentences(annotator, valid_sentences, options)?;
+    let mut proto_sentences: Vec<_> = annotated_sentences
+        .into_iter()
+        .map(|(sentence, scores, error)| {
+            let mut proto_sentence = sentences::proto::Sentence::from(sentence);
+            sentences::add_scores(&mut proto_sentence, scores);
+            proto_sentence.status = error.as_ref().map(Into::into);
+            proto_sentence
+        })
+        .collect();
+
+    // Restore the order of the sentences.
+    for (idx, sentence) in invalid_sentences {
+        proto_sentences.insert(idx, sentence);
+    }
+
+    Ok(sentences::proto::Sentences {
+        sentences: proto_sentences,
+    })
+}
+
+/// Annotate sentences, keeping their multiword tokens and empty nodes.
+///
+/// The sentences are decoded using their constraints. Returns the
+/// annotated sentences with their scores and errors.
+fn annotate_extended_sentences(
+    annotator: &Annotator,
+    mut sentences: Vec<ExtendedSentence>,
+    options: &AnnotateOptions,
+) -> Result<Vec<(ExtendedSentence, SentenceScores, Option<AnnotatorError>)>, AnnotatorError> {
+    let annotated_sentences = annotator.annotate_constrained_sentences(
+        sentences.iter_mut().map(|s| {
+            (
+                mem::replace(&mut s.sentence, Sentence::new()),
+                mem::take(&mut s.constraints),
+            )
+        }),
+        options,
+    )?;
+
+    Ok(sentences
+        .into_iter()
+        .zip(annotated_sentences)
+        .map(|(mut sentence, annotated_sentence)| {
+            sentence.sentence = annotated_sentence.sentence;
+            (
+                sentence,
+                annotated_sentence.scores,
+                annotated_sentence.error,
+            )
+        })
+        .collect())
+}
+
+/// Annotate the given sentences in CoNLL-U format.
+///
+/// The annotated sentences are returned in CoNLL-U format.
+#[no_mangle]
+pub extern "C" fn syntaxdot_annotator_annotate_conllu(
+    handle: u64,
+    conllu: FfiStr<'_>,
+    batch_size: usize,
+    err: &mut ExternError,
+) -> *mut c_char {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences = conll::read_sentences(conllu.as_str())?;
+        let options = AnnotateOptions {
+            batch_size,
+            ..Default::default()
+        };
+        let annotated_sentences = annotate_extended_sentences(annotator, sentences, &options)?;
+        conll::write_sentences(annotated_sentences.iter().map(|(sentence, _, _)| sentence))
+            .map_err(Into::into)
+    })
+}
+
+/// Annotate the given sentences in JSON format.
+///
+/// The JSON encoding of sentences uses the fields of the `Sentences`
+/// protobuf message. The annotated sentences are returned in the same
+/// format.
+#[no_mangle]
+pub extern "C" fn syntaxdot_annotator_annotate_json(
+    handle: u64,
+    json: FfiStr<'_>,
+    batch_size: usize,
+    err: &mut ExternError,
+) -> *mut c_char {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            serde_json::from_str(json.as_str()).map_err(AnnotatorError::Json)?;
+        let options = AnnotateOptions {
+            batch_size,
+            ..Default::default()
+        };
+        let annotated_sentences = annotate_proto_sentences(annotator, sentences, &options)?;
+        serde_json::to_string(&annotated_sentences)
+            .map_err(AnnotatorError::Json)
+            .map_err(Into::into)
+    })
+}
+
+/// Annotate raw text.
+///
+/// The text is split into sentences and tokens using the rule-based
+/// segmenter, configured with the given serialized `SegmenterOptions`.
+/// The annotated sentences are returned as serialized `Sentences`.
+///
+/// # Safety
+///
+/// Safe use of this function requires a valid pointer `options_data` and
+/// a correct length `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_annotate_text(
+    handle: u64,
+    text: FfiStr<'_>,
+    options_data: *const u8,
+    options_data_len: i32,
+    batch_size: usize,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let options: segmenter::proto::SegmenterOptions =
+            prost::Message::decode(get_buffer(options_data, options_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let sentences = Segmenter::from(options).segment(text.as_str());
+        let annotate_options = AnnotateOptions {
+            batch_size,
+            ..Default::default()
+        };
         let annotated_sentences = annotator
-            .annotate_sentences(sentences.0, batch_size)?
+            .annotate_sentences(sentences, &annotate_options)?
             .into_iter()
             .map(|s| s.sentence)
             .collect::<Vec<_>>();
@@ -64,14 +370,271 @@ pub unsafe extern "C" fn syntaxdot_annotator_annotate(
     })
 }
 
+/// Compute token embeddings for the given sentences.
+///
+/// The sentences must be serialized `Sentences`, the options a serialized
+/// `EmbedOptions` protobuf message or an empty buffer to use the default
+/// options. The embeddings are returned as serialized `Embeddings`.
+///
+/// # Safety
+///
+/// Safe use of this function requires valid pointers `sentences_data` and
+/// `options_data` with correct lengths `sentences_data_len` and
+/// `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_embed(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            prost::Message::decode(get_buffer(sentences_data, sentences_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: options::proto::EmbedOptions =
+            prost::Message::decode(get_buffer(options_data, options_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: EmbedOptions = options.try_into()?;
+        let sentences = sentences
+            .sentences
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<Sentence>, _>>()?;
+        let embeddings = annotator.embed_sentences(sentences, &options)?;
+        Ok(embeddings::proto::Embeddings::from(embeddings))
+    })
+}
+
+/// Compute sentence embeddings for the given sentences.
+///
+/// The sentences must be serialized `Sentences`, the options a serialized
+/// `EmbedOptions` protobuf message or an empty buffer to use the default
+/// options. The embeddings are returned as serialized `SentenceEmbeddings`.
+///
+/// # Safety
+///
+/// Safe use of this function requires valid pointers `sentences_data` and
+/// `options_data` with correct lengths `sentences_data_len` and
+/// `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_embed_sentences(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            prost::Message::decode(get_buffer(sentences_data, sentences_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: options::proto::EmbedOptions =
+            prost::Message::decode(get_buffer(options_data, options_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: EmbedOptions = options.try_into()?;
+        let sentences = sentences
+            .sentences
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<Sentence>, _>>()?;
+        let embeddings = annotator.embed_sentences_pooled(sentences, &options)?;
+        Ok(embeddings::proto::SentenceEmbeddings::from(embeddings))
+    })
+}
+
+/// Split the given sentences into word pieces.
+///
+/// The sentences must be serialized `Sentences`. The word pieces and
+/// the alignment of tokens to pieces are returned as serialized
+/// `TokenizedSentences`. Sentences are not annotated.
+///
+/// # Safety
+///
+/// Safe use of this function requires a valid pointer `sentences_data`
+/// with correct length `sentences_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_tokenize(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    ANNOTATORS.call_with_result(err, handle, |annotator| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            prost::Message::decode(get_buffer(sentences_data, sentences_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let sentences = sentences
+            .sentences
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<Sentence>, _>>()?;
+        let tokenized = annotator
+            .tokenize_sentences(sentences)
+            .iter()
+            .map(|sentence| annotator.piece_vocab().tokenized_sentence(sentence))
+            .collect();
+        Ok(pieces::proto::TokenizedSentences {
+            sentences: tokenized,
+        })
+    })
+}
+
 /// Load a syntaxdot annotator.
 #[no_mangle]
 pub extern "C" fn syntaxdot_annotator_load(config_path: FfiStr<'_>, err: &mut ExternError) -> u64 {
+    let _load_guard = LOAD_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
     ANNOTATORS.insert_with_result(err, || -> Result<Annotator, ExternError> {
-        Annotator::load(Device::Cpu, config_path.as_str()).map_err(Into::into)
+        Annotator::load(config_path.as_str(), &AnnotatorOptions::default()).map_err(Into::into)
     })
 }
 
+/// Load a syntaxdot annotator with the given options.
+///
+/// Thread settings are only applied after the annotator was loaded
+/// successfully.
+///
+/// # Safety
+///
+/// Safe use of this function requires a valid pointer `options_data` and
+/// a correct length `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_load_with_options(
+    config_path: FfiStr<'_>,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> u64 {
+    let _load_guard = LOAD_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    ANNOTATORS.insert_with_result(err, || -> Result<Annotator, ExternError> {
+        let options = decode_options(get_buffer(options_data, options_data_len))?;
+        options.check_num_threads(ANNOTATORS.len())?;
+        let annotator = Annotator::load(config_path.as_str(), &options)?;
+        options.set_num_threads();
+        Ok(annotator)
+    })
+}
+
+/// Load a syntaxdot annotator from model data in memory.
+///
+/// The model data must be a serialized `ModelData` protobuf message,
+/// the options a serialized `AnnotatorOptions` message. Thread settings
+/// are only applied after the annotator was loaded successfully.
+///
+/// # Safety
+///
+/// Safe use of this function requires valid pointers `model_data` and
+/// `options_data` with correct lengths `model_data_len` and
+/// `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_annotator_load_from_data(
+    model_data: *const u8,
+    model_data_len: i32,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> u64 {
+    let _load_guard = LOAD_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    ANNOTATORS.insert_with_result(err, || -> Result<Annotator, ExternError> {
+        let model_data: model::proto::ModelData =
+            prost::Message::decode(get_buffer(model_data, model_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options = decode_options(get_buffer(options_data, options_data_len))?;
+        options.check_num_threads(ANNOTATORS.len())?;
+        let annotator = Annotator::load_from_data(&model_data, &options)?;
+        options.set_num_threads();
+        Ok(annotator)
+    })
+}
+
+/// Open a streaming annotation session.
+///
+/// The options must be a serialized `AnnotateOptions` protobuf message,
+/// or an empty buffer to use the default options. The session must be
+/// closed before the annotator is freed.
+///
+/// # Safety
+///
+/// Safe use of this function requires a valid pointer `options_data`
+/// and a correct length `options_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_session_open(
+    handle: u64,
+    options_data: *const u8,
+    options_data_len: i32,
+    err: &mut ExternError,
+) -> u64 {
+    SESSIONS.insert_with_result(err, || -> Result<Session, ExternError> {
+        let options: options::proto::AnnotateOptions =
+            prost::Message::decode(get_buffer(options_data, options_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        let options: AnnotateOptions = options.try_into()?;
+        ANNOTATORS.get_u64(handle, |annotator| -> Result<_, ExternError> {
+            annotator.check_annotate_options(&options)?;
+            Ok(())
+        })?;
+        Ok(Session::new(handle, options))
+    })
+}
+
+/// Push sentences to a streaming annotation session.
+///
+/// The sentences must be serialized `Sentences`. Sentences are
+/// annotated once a full batch is pending. Fails without adding the
+/// sentences when the session would buffer more sentences than it
+/// allows or when the head of a token is not in its sentence.
+///
+/// # Safety
+///
+/// Safe use of this function requires a valid pointer `sentences_data`
+/// and a correct length `sentences_data_len`.
+#[no_mangle]
+pub unsafe extern "C" fn syntaxdot_session_push(
+    handle: u64,
+    sentences_data: *const u8,
+    sentences_data_len: i32,
+    err: &mut ExternError,
+) {
+    SESSIONS.call_with_result_mut(err, handle, |session| -> Result<_, ExternError> {
+        let sentences: sentences::proto::Sentences =
+            prost::Message::decode(get_buffer(sentences_data, sentences_data_len))
+                .map_err(AnnotatorError::ProtobufDecode)?;
+        ANNOTATORS.get_u64(session.annotator(), |annotator| {
+            session
+                .push(annotator, sentences.sentences)
+                .map_err(Into::into)
+        })
+    })
+}
+
+/// Poll a streaming annotation session for annotated sentences.
+///
+/// Returns the sentences that were annotated since the previous poll as
+/// serialized `Sentences`, in the order in which they were pushed. If
+/// `flush` is true, pending sentences that do not fill a batch are
+/// annotated as well.
+#[no_mangle]
+pub extern "C" fn syntaxdot_session_poll(
+    handle: u64,
+    flush: bool,
+    err: &mut ExternError,
+) -> ByteBuffer {
+    SESSIONS.call_with_result_mut(err, handle, |session| -> Result<_, ExternError> {
+        ANNOTATORS.get_u64(session.annotator(), |annotator| {
+            session.poll(annotator, flush).map_err(Into::into)
+        })
+    })
+}
+
+fn decode_options(buffer: &[u8]) -> Result<AnnotatorOptions, AnnotatorError> {
+    let options: options::proto::AnnotatorOptions =
+        prost::Message::decode(buffer).map_err(AnnotatorError::ProtobufDecode)?;
+    options.try_into()
+}
+
 /// Set the number of inter-op threads.
 #[no_mangle]
 pub extern "C" fn syntaxdot_set_num_interop_threads(n_threads: i32) {
@@ -84,6 +647,25 @@ pub extern "C" fn syntaxdot_set_num_intraop_threads(n_threads: i32) {
     tch::set_num_threads(n_threads);
 }
 
+/// Set the number of worker threads for asynchronous calls.
+///
+/// The worker threads are started by the first asynchronous call. An
+/// error is returned when the workers were already started.
+#[no_mangle]
+pub extern "C" fn syntaxdot_set_num_workers(n_workers: usize, err: &mut ExternError) {
+    ffi_support::call_with_result(err, || -> Result<_, AnnotatorError> {
+        match NUM_WORKERS.lock().unwrap().as_mut() {
+            Some(num_workers) => {
+                *num_workers = n_workers;
+                Ok(())
+            }
+            None => Err(AnnotatorError::InvalidOption(
+                "the workers were already started".to_string(),
+            )),
+        }
+    })
+}
+
 /// Get the syntaxdot version.
 ///
 /// The returned string must not be deallocated.
@@ -95,11 +677,38 @@ pub extern "C" fn syntaxdot_version() -> *const c_char {
 #[cfg(test)]
 mod tests {
     use std::ffi::CString;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Mutex;
 
-    use ffi_support::{ErrorCode, ExternError, FfiStr};
+    use ffi_support::{ByteBuffer, ErrorCode, ExternError, FfiStr};
+    use prost::Message;
 
-    use crate::error::error_codes::IO_ERROR;
-    use crate::syntaxdot_annotator_load;
+    use crate::error::error_codes::{INVALID_OPTION_ERROR, IO_ERROR, SYNTAXDOT_ERROR};
+    use crate::model::proto::ModelData;
+    use crate::options::proto::AnnotatorOptions;
+    use crate::{
+        syntaxdot_annotator_annotate_async, syntaxdot_annotator_load,
+        syntaxdot_annotator_load_from_data, syntaxdot_annotator_load_with_options,
+        syntaxdot_set_num_workers, WORKERS,
+    };
+
+    fn load_with_options(options: AnnotatorOptions) -> ExternError {
+        let mut err = ExternError::default();
+        let config_path = CString::new("/foo/bar/baz").unwrap();
+        let mut options_proto = Vec::new();
+        options.encode(&mut options_proto).unwrap();
+        let _handle = unsafe {
+            syntaxdot_annotator_load_with_options(
+                FfiStr::from_cstr(&config_path),
+                options_proto.as_ptr(),
+                options_proto.len() as i32,
+                &mut err,
+            )
+        };
+        err
+    }
 
     #[test]
     fn model_cannot_be_loaded() {
@@ -108,23 +717,190 @@ mod tests {
         let _handle = syntaxdot_annotator_load(FfiStr::from_cstr(&config_path), &mut err);
         assert_eq!(err.get_code(), ErrorCode::new(IO_ERROR));
     }
+
+    #[test]
+    fn model_cannot_be_loaded_from_invalid_data() {
+        let mut err = ExternError::default();
+        let mut model_proto = Vec::new();
+        ModelData {
+            config: b"[model]".to_vec(),
+            ..Default::default()
+        }
+        .encode(&mut model_proto)
+        .unwrap();
+        let _handle = unsafe {
+            syntaxdot_annotator_load_from_data(
+                model_proto.as_ptr(),
+                model_proto.len() as i32,
+                std::ptr::null(),
+                0,
+                &mut err,
+            )
+        };
+        assert_eq!(err.get_code(), ErrorCode::new(SYNTAXDOT_ERROR));
+    }
+
+    extern "C" fn send_async_result(user_data: *mut c_void, result: ByteBuffer, err: ExternError) {
+        let sender = unsafe { &*(user_data as *const Mutex<Sender<(ErrorCode, Vec<u8>)>>) };
+        sender
+            .lock()
+            .unwrap()
+            .send((err.get_code(), result.destroy_into_vec()))
+            .unwrap();
+        unsafe { err.manually_release() };
+    }
+
+    #[test]
+    fn async_annotate_reports_errors_to_callback() {
+        let (sender, receiver) = mpsc::channel::<(ErrorCode, Vec<u8>)>();
+        let sender = Mutex::new(sender);
+
+        // 0 is never a valid handle.
+        unsafe {
+            syntaxdot_annotator_annotate_async(
+                0,
+                ptr::null(),
+                0,
+                ptr::null(),
+                0,
+                send_async_result,
+                &sender as *const _ as *mut c_void,
+            )
+        };
+
+        let (code, result) = receiver.recv().unwrap();
+        assert_eq!(code, ErrorCode::INVALID_HANDLE);
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn num_workers_cannot_be_set_after_workers_are_started() {
+        lazy_static::initialize(&WORKERS);
+
+        let mut err = ExternError::default();
+        syntaxdot_set_num_workers(2, &mut err);
+        assert_eq!(err.get_code(), ErrorCode::new(INVALID_OPTION_ERROR));
+    }
+
+    #[test]
+    fn default_options_are_accepted() {
+        // Fails after option validation, since the configuration does not exist.
+        let err = load_with_options(AnnotatorOptions::default());
+        assert_eq!(err.get_code(), ErrorCode::new(IO_ERROR));
+    }
 }
 
 #[cfg(feature = "model-tests")]
 #[cfg(test)]
 mod model_tests {
+    use std::convert::TryFrom;
     use std::env;
     use std::ffi::CString;
+    use std::fs::{self, File};
     use std::iter::FromIterator;
+    use std::os::raw::c_void;
+    use std::ptr;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Mutex;
 
-    use ffi_support::{ErrorCode, ExternError, FfiStr};
+    use ffi_support::{ByteBuffer, ErrorCode, ExternError, FfiStr};
     use pretty_assertions::assert_eq;
     use prost::Message;
-    use udgraph::graph::{DepTriple, Sentence};
-    use udgraph::token::{Features, Token, TokenBuilder};
+    use syntaxdot::config::{Config, Tokenizer, TomlRead};
+    use syntaxdot::encoders::EncoderType;
+    use syntaxdot_encoders::layer::Layer;
+    use udgraph::graph::{DepTriple, Node, Sentence};
+    use udgraph::token::{Features, Misc, Token, TokenBuilder};
+
+    use crate::conll::{read_sentences, write_sentences};
+    use crate::embeddings::proto::{Embeddings, SentenceEmbeddings};
+    use crate::error::error_codes::{
+        INVALID_CONSTRAINT_ERROR, INVALID_OPTION_ERROR, SEQUENCE_TOO_LONG_ERROR,
+        UNKNOWN_ENCODER_ERROR,
+    };
+    use crate::model::proto::ModelData;
+    use crate::options::proto::{
+        AnnotateOptions, AnnotationPolicy, AnnotatorOptions, EmbedOptions, HeadScores,
+        LongSentences,
+    };
+    use crate::pieces::proto::TokenizedSentences;
+    use crate::sentences::{proto, ExtendedSentence, Sentences};
+    use crate::tagger::{HEAD, RELATION};
+    use crate::{
+        syntaxdot_annotator_annotate, syntaxdot_annotator_annotate_async,
+        syntaxdot_annotator_annotate_conllu, syntaxdot_annotator_annotate_json,
+        syntaxdot_annotator_annotate_text, syntaxdot_annotator_annotate_with_options,
+        syntaxdot_annotator_embed, syntaxdot_annotator_embed_sentences, syntaxdot_annotator_free,
+        syntaxdot_annotator_load, syntaxdot_annotator_load_from_data,
+        syntaxdot_annotator_load_with_options, syntaxdot_annotator_tokenize,
+        syntaxdot_session_close, syntaxdot_session_open, syntaxdot_session_poll,
+        syntaxdot_session_push,
+    };
+
+    fn test_model_config_path() -> String {
+        format!("{}/syntaxdot.conf", env::var("DUTCH_UD_MEDIUM").unwrap())
+    }
+
+    /// Load the test model, run `f` with its handle and free the model.
+    fn with_test_model(f: impl FnOnce(u64)) {
+        let mut err = ExternError::default();
+        let config_path = CString::new(test_model_config_path()).unwrap();
+        let handle = syntaxdot_annotator_load(FfiStr::from_cstr(&config_path), &mut err);
+        assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+        f(handle);
+
+        syntaxdot_annotator_free(handle, &mut err);
+        assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+    }
+
+    /// Load the test model with `options`, run `f` with its handle and
+    /// free the model.
+    fn with_test_model_options(options: AnnotatorOptions, f: impl FnOnce(u64)) {
+        let mut options_proto = Vec::new();
+        options.encode(&mut options_proto).unwrap();
+
+        let mut err = ExternError::default();
+        let config_path = CString::new(test_model_config_path()).unwrap();
+        let handle = unsafe {
+            syntaxdot_annotator_load_with_options(
+                FfiStr::from_cstr(&config_path),
+                options_proto.as_ptr(),
+                options_proto.len() as i32,
+                &mut err,
+            )
+        };
+        assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+        f(handle);
+
+        syntaxdot_annotator_free(handle, &mut err);
+        assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+    }
+
+    fn test_model_data(config_path: &str) -> ModelData {
+        let mut config = Config::from_toml_read(File::open(config_path).unwrap()).unwrap();
+        config.relativize_paths(config_path).unwrap();
+
+        let vocab = match &config.input.tokenizer {
+            Tokenizer::Albert { vocab } => vocab,
+            Tokenizer::Bert { vocab } => vocab,
+            Tokenizer::XlmRoberta { vocab } => vocab,
+        };
 
-    use crate::sentences::{proto, Sentences};
-    use crate::{syntaxdot_annotator_annotate, syntaxdot_annotator_free, syntaxdot_annotator_load};
+        ModelData {
+            config: fs::read(config_path).unwrap(),
+            biaffine_labels: config
+                .biaffine
+                .as_ref()
+                .map(|biaffine| fs::read(&biaffine.labels).unwrap())
+                .unwrap_or_default(),
+            labels: fs::read(&config.labeler.labels).unwrap(),
+            vocab: fs::read(vocab).unwrap(),
+            pretrain_config: fs::read(&config.model.pretrain_config).unwrap(),
+            parameters: fs::read(&config.model.parameters).unwrap(),
+        }
+    }
 
     fn test_sentence_protobuf() -> Vec<u8> {
         let tokens = vec![
@@ -208,7 +984,7 @@ mod model_tests {
 
     #[test]
     fn model_can_be_loaded() {
-        let model_config_path = format!("{}/syntaxdot.conf", env::var("DUTCH_UD_MEDIUM").unwrap());
+        let model_config_path = test_model_config_path();
 
         let mut err = ExternError::default();
 
@@ -229,18 +1005,27 @@ mod model_tests {
     }
 
     #[test]
-    fn model_gives_correct_output() {
-        let model_config_path = format!("{}/syntaxdot.conf", env::var("DUTCH_UD_MEDIUM").unwrap());
+    fn model_can_be_loaded_from_data() {
+        let model_config_path = test_model_config_path();
 
-        let mut err = ExternError::default();
+        let mut model_proto = Vec::new();
+        test_model_data(&model_config_path)
+            .encode(&mut model_proto)
+            .unwrap();
 
-        // Check that a model can be loaded.
-        let config_path = CString::new(model_config_path.as_str()).unwrap();
-        let handle = syntaxdot_annotator_load(FfiStr::from_cstr(&config_path), &mut err);
+        let mut err = ExternError::default();
+        let handle = unsafe {
+            syntaxdot_annotator_load_from_data(
+                model_proto.as_ptr(),
+                model_proto.len() as i32,
+                std::ptr::null(),
+                0,
+                &mut err,
+            )
+        };
         assert_eq!(err.get_code(), ErrorCode::SUCCESS);
 
         let sentences_proto = test_sentence_protobuf();
-
         let buffer = unsafe {
             syntaxdot_annotator_annotate(
                 handle,
@@ -252,12 +1037,1249 @@ mod model_tests {
         };
         assert_eq!(err.get_code(), ErrorCode::SUCCESS);
 
-        let annotated_sentences: Sentences =
-            proto::Sentences::decode(buffer.as_slice()).unwrap().into();
+        let annotated_sentences =
+            Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap()).unwrap();
         assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
 
         let mut err = ExternError::default();
         syntaxdot_annotator_free(handle, &mut err);
         assert_eq!(err.get_code(), ErrorCode::SUCCESS);
     }
+
+    #[test]
+    fn model_with_unknown_encoder_cannot_be_loaded() {
+        let model_config_path = test_model_config_path();
+
+        let mut err = ExternError::default();
+
+        let options = AnnotatorOptions {
+            encoders: vec!["nonexistent".to_string()],
+            ..Default::default()
+        };
+        let mut options_proto = Vec::new();
+        options.encode(&mut options_proto).unwrap();
+
+        let config_path = CString::new(model_config_path.as_str()).unwrap();
+        let _handle = unsafe {
+            syntaxdot_annotator_load_with_options(
+                FfiStr::from_cstr(&config_path),
+                options_proto.as_ptr(),
+                options_proto.len() as i32,
+                &mut err,
+            )
+        };
+        assert_eq!(err.get_code(), ErrorCode::new(UNKNOWN_ENCODER_ERROR));
+    }
+
+    #[test]
+    fn model_gives_correct_output() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences =
+                Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap()).unwrap();
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+        });
+    }
+
+    #[test]
+    fn model_gives_correct_output_with_max_batch_pieces() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentence = proto::Sentences::decode(test_sentence_protobuf().as_slice())
+                .unwrap()
+                .sentences
+                .remove(0);
+            let sentences = proto::Sentences {
+                sentences: vec![sentence; 3],
+            };
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            // Every sentence exceeds the limit, so it gets its own batch.
+            let options = AnnotateOptions {
+                max_batch_pieces: 1,
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences =
+                Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap()).unwrap();
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check(); 3]);
+        });
+    }
+
+    extern "C" fn send_annotated(user_data: *mut c_void, result: ByteBuffer, err: ExternError) {
+        let sender = unsafe { &*(user_data as *const Mutex<Sender<Result<Sentences, String>>>) };
+        let annotated = match unsafe { err.get_and_consume_message() } {
+            Some(message) => Err(message),
+            None => Ok(Sentences::try_from(
+                proto::Sentences::decode(result.destroy_into_vec().as_slice()).unwrap(),
+            )
+            .unwrap()),
+        };
+        sender.lock().unwrap().send(annotated).unwrap();
+    }
+
+    #[test]
+    fn model_gives_correct_async_output() {
+        with_test_model(|handle| {
+            let (sender, receiver) = mpsc::channel::<Result<Sentences, String>>();
+            let sender = Mutex::new(sender);
+
+            let sentences_proto = test_sentence_protobuf();
+            for _ in 0..2 {
+                unsafe {
+                    syntaxdot_annotator_annotate_async(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        ptr::null(),
+                        0,
+                        send_annotated,
+                        &sender as *const _ as *mut c_void,
+                    )
+                };
+            }
+
+            for _ in 0..2 {
+                let annotated_sentences = receiver.recv().unwrap().unwrap();
+                assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+            }
+        });
+    }
+
+    #[test]
+    fn model_gives_correct_conllu_output() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentence = Sentence::from_iter(vec![
+                Token::new("Dit"),
+                Token::new("is"),
+                Token::new("een"),
+                Token::new("test"),
+                Token::new("."),
+            ]);
+            let conllu =
+                CString::new(write_sentences(&[ExtendedSentence::from(sentence)]).unwrap())
+                    .unwrap();
+
+            let annotated = syntaxdot_annotator_annotate_conllu(
+                handle,
+                FfiStr::from_cstr(&conllu),
+                32,
+                &mut err,
+            );
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated = unsafe { CString::from_raw(annotated) }
+                .into_string()
+                .unwrap();
+            assert_eq!(
+                read_sentences(&annotated).unwrap(),
+                vec![ExtendedSentence::from(test_sentence_check())]
+            );
+        });
+    }
+
+    #[test]
+    fn model_gives_correct_json_output() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let json = CString::new(
+                r#"{"sentences": [{"tokens": [
+                    {"form": "Dit"}, {"form": "is"}, {"form": "een"}, {"form": "test"}, {"form": "."}
+                ]}]}"#,
+            )
+            .unwrap();
+
+            let annotated =
+                syntaxdot_annotator_annotate_json(handle, FfiStr::from_cstr(&json), 32, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated = unsafe { CString::from_raw(annotated) }
+                .into_string()
+                .unwrap();
+            let annotated_sentences =
+                Sentences::try_from(serde_json::from_str::<proto::Sentences>(&annotated).unwrap())
+                    .unwrap();
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+        });
+    }
+
+    #[test]
+    fn model_gives_correct_text_output() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let text = CString::new("Dit is een test.").unwrap();
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_text(
+                    handle,
+                    FfiStr::from_cstr(&text),
+                    std::ptr::null(),
+                    0,
+                    32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let mut annotated_sentences =
+                Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap()).unwrap();
+
+            // Offsets are checked by the segmenter tests.
+            for token in annotated_sentences.0[0]
+                .iter_mut()
+                .filter_map(Node::token_mut)
+            {
+                token.set_misc(Misc::new());
+            }
+
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+        });
+    }
+
+    #[test]
+    fn model_preserves_offsets() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let mut start = 0;
+            let tokens = ["Dit", "is", "een", "test", "."]
+                .iter()
+                .enumerate()
+                .map(|(idx, form)| {
+                    let end = start + form.chars().count() as u32;
+                    let token = proto::Token {
+                        form: form.to_string(),
+                        start,
+                        end,
+                        no_space_after: idx == 3,
+                        ..Default::default()
+                    };
+                    start = if idx == 3 { end } else { end + 1 };
+                    token
+                })
+                .collect::<Vec<_>>();
+            let sentences = proto::Sentences {
+                sentences: vec![proto::Sentence {
+                    tokens: tokens.clone(),
+                    ..Default::default()
+                }],
+            };
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+            let offsets = annotated_sentences.sentences[0]
+                .tokens
+                .iter()
+                .map(|token| (token.start, token.end, token.no_space_after))
+                .collect::<Vec<_>>();
+            let expected_offsets = tokens
+                .iter()
+                .map(|token| (token.start, token.end, token.no_space_after))
+                .collect::<Vec<_>>();
+            assert_eq!(offsets, expected_offsets);
+        });
+    }
+
+    #[test]
+    fn model_preserves_sentence_metadata() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            // Sentences of different lengths, so that they are reordered
+            // during annotation.
+            let texts = ["Dit is een lange test .", "Dit is een test .", "Test ."];
+            let sentences = proto::Sentences {
+                sentences: texts
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, text)| proto::Sentence {
+                        tokens: text
+                            .split(' ')
+                            .map(|form| proto::Token {
+                                form: form.to_string(),
+                                ..Default::default()
+                            })
+                            .collect(),
+                        id: format!("s{}", idx),
+                        metadata: vec![proto::Comment {
+                            key: "text".to_string(),
+                            value: text.to_string(),
+                        }],
+                        ..Default::default()
+                    })
+                    .collect(),
+            };
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    2,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+            for (sentence, annotated_sentence) in sentences
+                .sentences
+                .iter()
+                .zip(annotated_sentences.sentences.iter())
+            {
+                assert_eq!(annotated_sentence.id, sentence.id);
+                assert_eq!(annotated_sentence.metadata, sentence.metadata);
+            }
+        });
+    }
+
+    #[test]
+    fn model_preserves_multiword_tokens_and_empty_nodes() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let conllu = "1\tDit\t_\t_\t_\t_\t_\t_\t_\t_\n\
+                          2-3\tiseen\t_\t_\t_\t_\t_\t_\t_\t_\n\
+                          2\tis\t_\t_\t_\t_\t_\t_\t_\t_\n\
+                          3\teen\t_\t_\t_\t_\t_\t_\t_\t_\n\
+                          4\ttest\t_\t_\t_\t_\t_\t_\t_\t_\n\
+                          4.1\tis\tzijn\tAUX\t_\t_\t_\t_\t4:cop\t_\n\
+                          5\t.\t_\t_\t_\t_\t_\t_\t_\t_\n\n";
+            let sentences = read_sentences(conllu).unwrap();
+            let conllu = CString::new(conllu).unwrap();
+
+            let annotated = syntaxdot_annotator_annotate_conllu(
+                handle,
+                FfiStr::from_cstr(&conllu),
+                32,
+                &mut err,
+            );
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated = unsafe { CString::from_raw(annotated) }
+                .into_string()
+                .unwrap();
+            let annotated_sentences = read_sentences(&annotated).unwrap();
+            assert_eq!(annotated_sentences[0].sentence, test_sentence_check());
+            assert_eq!(
+                annotated_sentences[0].multiword_tokens,
+                sentences[0].multiword_tokens
+            );
+            assert_eq!(annotated_sentences[0].empty_nodes, sentences[0].empty_nodes);
+        });
+    }
+
+    #[test]
+    fn model_preserves_enhanced_dependencies() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let enhanced_dependencies = ["4:nsubj", "4:cop", "4:det", "0:root", "4:punct"];
+            let mut sentence = test_sentence_check();
+            for (token, deps) in sentence
+                .iter_mut()
+                .filter_map(Node::token_mut)
+                .zip(enhanced_dependencies.iter())
+            {
+                token.set_deps(Some(*deps));
+            }
+
+            let sentences = proto::Sentences::from(Sentences(vec![sentence.clone()]));
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences =
+                Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap()).unwrap();
+            assert_eq!(annotated_sentences.0, vec![sentence]);
+        });
+    }
+
+    #[test]
+    fn model_gives_confidences() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+            let options = AnnotateOptions {
+                confidences: true,
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+            for token in &annotated_sentences.sentences[0].tokens {
+                assert!(token.confidences.contains_key(HEAD));
+                assert!(token.confidences.contains_key(RELATION));
+                assert!(token.confidences.len() > 2);
+                assert!(token
+                    .confidences
+                    .values()
+                    .all(|&prob| prob > 0. && prob <= 1.));
+            }
+
+            // Confidences do not change the annotations.
+            let annotated_sentences = Sentences::try_from(annotated_sentences).unwrap();
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+
+            // Confidences are only returned when requested.
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    std::ptr::null(),
+                    0,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+            assert!(annotated_sentences.sentences[0]
+                .tokens
+                .iter()
+                .all(|token| token.confidences.is_empty()));
+        });
+    }
+
+    #[test]
+    fn model_respects_annotation_policies() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let annotate = |policy: AnnotationPolicy, err: &mut ExternError| {
+                let mut sentence = Sentence::from_iter(vec![
+                    Token::new("Dit"),
+                    Token::new("is"),
+                    Token::new("een"),
+                    Token::new("test"),
+                    Token::new("."),
+                ]);
+                sentence
+                    .dep_graph_mut()
+                    .add_deprel(DepTriple::new(3, Some("det"), 1));
+                let sentences = proto::Sentences::from(Sentences(vec![sentence]));
+                let mut sentences_proto = Vec::new();
+                sentences.encode(&mut sentences_proto).unwrap();
+
+                let options = AnnotateOptions {
+                    confidences: true,
+                    policies: vec![(HEAD.to_string(), policy as i32)]
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                };
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate_with_options(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        err,
+                    )
+                };
+                proto::Sentences::decode(buffer.as_slice()).unwrap()
+            };
+
+            // The dependency relation of the first token is kept.
+            let annotated_sentences = annotate(AnnotationPolicy::KeepIfPresent, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            assert!(!tokens[0].confidences.contains_key(HEAD));
+            assert!(tokens[1..]
+                .iter()
+                .all(|token| token.confidences.contains_key(HEAD)));
+
+            let mut check = test_sentence_check();
+            check
+                .dep_graph_mut()
+                .add_deprel(DepTriple::new(3, Some("det"), 1));
+            let annotated_sentences = Sentences::try_from(annotated_sentences).unwrap();
+            assert_eq!(annotated_sentences.0, vec![check]);
+
+            // No other dependency relations are added when the parser is skipped.
+            let annotated_sentences = annotate(AnnotationPolicy::Skip, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            assert_eq!((tokens[0].head, tokens[0].relation.as_str()), (3, "det"));
+            assert!(tokens[1..].iter().all(|token| token.relation.is_empty()));
+            assert!(tokens
+                .iter()
+                .all(|token| !token.confidences.contains_key(HEAD)));
+
+            // Sequence labels are still assigned.
+            assert!(tokens.iter().all(|token| !token.upos.is_empty()));
+        });
+    }
+
+    #[test]
+    fn model_parses_with_fixed_dependency_relations() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let annotate = |fixed: Vec<DepTriple<&str>>, err: &mut ExternError| {
+                let mut sentence = Sentence::from_iter(vec![
+                    Token::new("Dit"),
+                    Token::new("is"),
+                    Token::new("een"),
+                    Token::new("test"),
+                    Token::new("."),
+                ]);
+                for triple in fixed {
+                    sentence.dep_graph_mut().add_deprel(triple);
+                }
+                let sentences = proto::Sentences::from(Sentences(vec![sentence]));
+                let mut sentences_proto = Vec::new();
+                sentences.encode(&mut sentences_proto).unwrap();
+
+                let options = AnnotateOptions {
+                    policies: vec![(HEAD.to_string(), AnnotationPolicy::KeepIfPresent as i32)]
+                        .into_iter()
+                        .collect(),
+                    ..Default::default()
+                };
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate_with_options(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        err,
+                    )
+                };
+                proto::Sentences::decode(buffer.as_slice()).unwrap()
+            };
+
+            // The model attaches "Dit" to "test", so attaching "test" to "Dit"
+            // requires another attachment of "Dit".
+            let annotated_sentences = annotate(vec![DepTriple::new(1, Some("appos"), 4)], &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            assert_eq!((tokens[3].head, tokens[3].relation.as_str()), (1, "appos"));
+
+            // The result is a tree: every token reaches the root.
+            for dependent in 1..=tokens.len() {
+                let mut node = dependent;
+                for _ in 0..=tokens.len() {
+                    if node == 0 {
+                        break;
+                    }
+                    assert!(!tokens[node - 1].relation.is_empty());
+                    node = tokens[node - 1].head as usize;
+                }
+                assert_eq!(node, 0);
+            }
+
+            // Fixed relations cannot form a cycle.
+            let _ = annotate(
+                vec![
+                    DepTriple::new(2, Some("nsubj"), 1),
+                    DepTriple::new(1, Some("cop"), 2),
+                ],
+                &mut err,
+            );
+            assert_eq!(err.get_code(), ErrorCode::new(INVALID_CONSTRAINT_ERROR));
+        });
+    }
+
+    #[test]
+    fn model_handles_long_sentences() {
+        let options = AnnotatorOptions {
+            max_len: 16,
+            ..Default::default()
+        };
+
+        with_test_model_options(options, |handle| {
+            let mut err = ExternError::default();
+
+            let forms = ["Dit", "is", "een", "test", "."];
+            let sentence =
+                Sentence::from_iter(forms.iter().cycle().take(20).map(|&form| Token::new(form)));
+            let sentences = proto::Sentences::from(Sentences(vec![sentence]));
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            let annotate = |long_sentences: LongSentences, err: &mut ExternError| {
+                let options = AnnotateOptions {
+                    long_sentences: long_sentences as i32,
+                    ..Default::default()
+                };
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate_with_options(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        err,
+                    )
+                };
+                proto::Sentences::decode(buffer.as_slice()).unwrap()
+            };
+
+            // Long sentences are rejected by default.
+            let _ = annotate(LongSentences::RejectLongSentences, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::new(SEQUENCE_TOO_LONG_ERROR));
+
+            // Only the first tokens of truncated sentences are annotated.
+            let mut err = ExternError::default();
+            let annotated_sentences = annotate(LongSentences::TruncateLongSentences, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            let n_annotated = tokens.iter().take_while(|token| !token.truncated).count();
+            assert!(n_annotated > 0 && n_annotated < tokens.len());
+            assert!(tokens[..n_annotated]
+                .iter()
+                .all(|token| !token.upos.is_empty() && !token.relation.is_empty()));
+            assert!(tokens[n_annotated..].iter().all(|token| token.truncated
+                && token.upos.is_empty()
+                && token.relation.is_empty()));
+
+            // All tokens are annotated with sliding windows.
+            let annotated_sentences = annotate(LongSentences::SlidingWindow, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            assert_eq!(tokens.len(), 20);
+            assert!(tokens.iter().all(|token| !token.truncated
+                && !token.upos.is_empty()
+                && !token.relation.is_empty()));
+        });
+    }
+
+    #[test]
+    fn model_reports_per_sentence_errors() {
+        let options = AnnotatorOptions {
+            max_len: 16,
+            ..Default::default()
+        };
+
+        with_test_model_options(options, |handle| {
+            let mut err = ExternError::default();
+
+            let forms = ["Dit", "is", "een", "test", "."];
+            let long_sentence =
+                Sentence::from_iter(forms.iter().cycle().take(20).map(|&form| Token::new(form)));
+            let mut cyclic_sentence =
+                Sentence::from_iter(forms.iter().map(|&form| Token::new(form)));
+            cyclic_sentence
+                .dep_graph_mut()
+                .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+            cyclic_sentence
+                .dep_graph_mut()
+                .add_deprel(DepTriple::new(1, Some("cop"), 2));
+            let mut sentences = proto::Sentences::from(Sentences(vec![
+                Sentence::from_iter(forms.iter().map(|&form| Token::new(form))),
+                long_sentence,
+                cyclic_sentence,
+            ]));
+
+            // A sentence with a head that is not in the sentence, followed
+            // by a sentence without errors.
+            let mut out_of_range_sentence = sentences.sentences[0].clone();
+            out_of_range_sentence.tokens[0].head = 42;
+            out_of_range_sentence.tokens[0].relation = "nsubj".to_string();
+            sentences.sentences.push(out_of_range_sentence);
+            sentences.sentences.push(sentences.sentences[0].clone());
+
+            let mut sentences_proto = Vec::new();
+            sentences.encode(&mut sentences_proto).unwrap();
+
+            let options = AnnotateOptions {
+                policies: vec![(HEAD.to_string(), AnnotationPolicy::KeepIfPresent as i32)]
+                    .into_iter()
+                    .collect(),
+                per_sentence_errors: true,
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+
+            // Sentences without errors are annotated.
+            let annotated = &annotated_sentences.sentences[0];
+            assert_eq!(annotated.status, None);
+            assert!(annotated.tokens.iter().all(|token| !token.upos.is_empty()));
+
+            // Sentences with errors are returned unchanged.
+            let status = annotated_sentences.sentences[1].status.as_ref().unwrap();
+            assert_eq!(status.error_code, SEQUENCE_TOO_LONG_ERROR);
+            assert_eq!(annotated_sentences.sentences[1], {
+                let mut sentence = sentences.sentences[1].clone();
+                sentence.status = Some(status.clone());
+                sentence
+            });
+
+            for idx in 2..4 {
+                let status = annotated_sentences.sentences[idx].status.as_ref().unwrap();
+                assert_eq!(status.error_code, INVALID_CONSTRAINT_ERROR);
+                assert_eq!(annotated_sentences.sentences[idx], {
+                    let mut sentence = sentences.sentences[idx].clone();
+                    sentence.status = Some(status.clone());
+                    sentence
+                });
+            }
+
+            // Sentences keep their order.
+            assert_eq!(annotated_sentences.sentences.len(), 5);
+            assert_eq!(
+                annotated_sentences.sentences[4],
+                annotated_sentences.sentences[0]
+            );
+        });
+    }
+
+    #[test]
+    fn model_annotates_selected_layers() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+            let annotate = |layers: Vec<String>, err: &mut ExternError| {
+                let options = AnnotateOptions {
+                    layers,
+                    ..Default::default()
+                };
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate_with_options(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        err,
+                    )
+                };
+                proto::Sentences::decode(buffer.as_slice()).unwrap()
+            };
+
+            // Only parse.
+            let annotated_sentences =
+                annotate(vec![HEAD.to_string(), RELATION.to_string()], &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let check = proto::Sentence::from(test_sentence_check());
+            for (token, check_token) in annotated_sentences.sentences[0]
+                .tokens
+                .iter()
+                .zip(&check.tokens)
+            {
+                assert_eq!(token.head, check_token.head);
+                assert_eq!(token.relation, check_token.relation);
+                assert!(token.upos.is_empty());
+                assert!(token.lemma.is_empty());
+            }
+
+            // Layers that the model does not assign are rejected.
+            let _ = annotate(vec!["nonexistent".to_string()], &mut err);
+            assert_eq!(err.get_code(), ErrorCode::new(UNKNOWN_ENCODER_ERROR));
+        });
+    }
+
+    #[test]
+    fn model_respects_allowed_labels() {
+        let model_config_path = test_model_config_path();
+
+        let config = Config::from_toml_read(File::open(&model_config_path).unwrap()).unwrap();
+        let upos_layer = config
+            .labeler
+            .encoders
+            .iter()
+            .find(|encoder| matches!(encoder.encoder, EncoderType::Sequence(Layer::UPos)))
+            .map(|encoder| encoder.name.clone())
+            .unwrap();
+
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let annotate = |labels: Vec<String>, err: &mut ExternError| {
+                let mut sentences =
+                    proto::Sentences::decode(test_sentence_protobuf().as_slice()).unwrap();
+                sentences.sentences[0].tokens[0].allowed_labels = vec![proto::AllowedLabels {
+                    layer: upos_layer.clone(),
+                    labels,
+                }];
+                let mut sentences_proto = Vec::new();
+                sentences.encode(&mut sentences_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        0,
+                        err,
+                    )
+                };
+                proto::Sentences::decode(buffer.as_slice()).unwrap()
+            };
+
+            // The model tags "Dit" as PRON, constrain it to other labels.
+            let annotated_sentences =
+                annotate(vec!["DET".to_string(), "NOUN".to_string()], &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let tokens = &annotated_sentences.sentences[0].tokens;
+            assert!(tokens[0].upos == "DET" || tokens[0].upos == "NOUN");
+            assert!(tokens[0].allowed_labels.is_empty());
+
+            // Other tokens are not constrained.
+            let check = proto::Sentence::from(test_sentence_check());
+            assert_eq!(tokens[1].upos, check.tokens[1].upos);
+
+            // Labels that the model does not assign are rejected.
+            let _ = annotate(vec!["NONEXISTENT".to_string()], &mut err);
+            assert_eq!(err.get_code(), ErrorCode::new(INVALID_CONSTRAINT_ERROR));
+        });
+    }
+
+    #[test]
+    fn model_gives_top_k_labels() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+            let options = AnnotateOptions {
+                top_k: vec![(HEAD.to_string(), 2), (RELATION.to_string(), 3)]
+                    .into_iter()
+                    .collect(),
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+            for token in &annotated_sentences.sentences[0].tokens {
+                assert!(token.confidences.is_empty());
+
+                let layers = token
+                    .top_k
+                    .iter()
+                    .map(|top_k| (top_k.layer.as_str(), top_k.labels.len()))
+                    .collect::<Vec<_>>();
+                assert_eq!(layers, vec![(HEAD, 2), (RELATION, 3)]);
+
+                for top_k in &token.top_k {
+                    assert!(top_k
+                        .labels
+                        .windows(2)
+                        .all(|pair| pair[0].probability >= pair[1].probability));
+                }
+
+                // The relation at the chosen head is the most probable relation.
+                assert_eq!(token.top_k[1].labels[0].label, token.relation);
+            }
+
+            // Top-k labels do not change the annotations.
+            let annotated_sentences = Sentences::try_from(annotated_sentences).unwrap();
+            assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+
+            // Layers that the model does not assign are rejected.
+            let options = AnnotateOptions {
+                top_k: vec![("nonexistent".to_string(), 2)].into_iter().collect(),
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let _buffer = unsafe {
+                syntaxdot_annotator_annotate_with_options(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::new(UNKNOWN_ENCODER_ERROR));
+        });
+    }
+
+    #[test]
+    fn model_gives_head_scores() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+
+            for head_scores in &[HeadScores::HeadScoreLogits, HeadScores::HeadProbabilities] {
+                let options = AnnotateOptions {
+                    head_scores: *head_scores as i32,
+                    ..Default::default()
+                };
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_annotate_with_options(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        &mut err,
+                    )
+                };
+                assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+                let annotated_sentences = proto::Sentences::decode(buffer.as_slice()).unwrap();
+                let sentence = &annotated_sentences.sentences[0];
+                let matrix = sentence.head_scores.as_ref().unwrap();
+                let n_tokens = sentence.tokens.len();
+                assert_eq!(matrix.n_tokens as usize, n_tokens);
+                assert_eq!(matrix.scores.len(), n_tokens * (n_tokens + 1));
+
+                if *head_scores == HeadScores::HeadProbabilities {
+                    for row in matrix.scores.chunks(n_tokens + 1) {
+                        assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-4);
+                    }
+                }
+
+                // Head scores do not change the annotations.
+                let annotated_sentences = Sentences::try_from(annotated_sentences).unwrap();
+                assert_eq!(annotated_sentences.0, vec![test_sentence_check()]);
+            }
+        });
+    }
+
+    #[test]
+    fn model_annotates_in_sessions() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let options = AnnotateOptions {
+                batch_size: 2,
+                ..Default::default()
+            };
+            let mut options_proto = Vec::new();
+            options.encode(&mut options_proto).unwrap();
+
+            let session = unsafe {
+                syntaxdot_session_open(
+                    handle,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+            let sentences_proto = test_sentence_protobuf();
+            let push_and_poll = |flush: bool| {
+                let mut err = ExternError::default();
+                unsafe {
+                    syntaxdot_session_push(
+                        session,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        &mut err,
+                    )
+                };
+                assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+
+                let buffer = syntaxdot_session_poll(session, flush, &mut err);
+                assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+                Sentences::try_from(proto::Sentences::decode(buffer.as_slice()).unwrap())
+                    .unwrap()
+                    .0
+            };
+
+            // Sentences are annotated when they fill a batch.
+            assert!(push_and_poll(false).is_empty());
+            assert_eq!(
+                push_and_poll(false),
+                vec![test_sentence_check(), test_sentence_check()]
+            );
+
+            // Flushing annotates a partial batch.
+            assert_eq!(push_and_poll(true), vec![test_sentence_check()]);
+
+            // Sentences with a head that is not in the sentence are not added.
+            let mut sentences = proto::Sentences::decode(sentences_proto.as_slice()).unwrap();
+            sentences.sentences[0].tokens[0].head = -1;
+            sentences.sentences[0].tokens[0].relation = "nsubj".to_string();
+            let mut invalid_proto = Vec::new();
+            sentences.encode(&mut invalid_proto).unwrap();
+            let mut err = ExternError::default();
+            unsafe {
+                syntaxdot_session_push(
+                    session,
+                    invalid_proto.as_ptr(),
+                    invalid_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::new(INVALID_CONSTRAINT_ERROR));
+            assert_eq!(push_and_poll(true), vec![test_sentence_check()]);
+
+            let mut err = ExternError::default();
+            syntaxdot_session_close(session, &mut err);
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+        });
+    }
+
+    #[test]
+    fn model_gives_embeddings() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+            let n_tokens = proto::Sentences::decode(sentences_proto.as_slice())
+                .unwrap()
+                .sentences[0]
+                .tokens
+                .len();
+
+            let embed = |options: EmbedOptions, err: &mut ExternError| {
+                let mut options_proto = Vec::new();
+                options.encode(&mut options_proto).unwrap();
+
+                let buffer = unsafe {
+                    syntaxdot_annotator_embed(
+                        handle,
+                        sentences_proto.as_ptr(),
+                        sentences_proto.len() as i32,
+                        options_proto.as_ptr(),
+                        options_proto.len() as i32,
+                        err,
+                    )
+                };
+
+                Embeddings::decode(buffer.as_slice())
+            };
+
+            let last_layer = embed(EmbedOptions::default(), &mut err).unwrap();
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            assert!(last_layer.dims > 0);
+            assert_eq!(last_layer.sentence_indices, vec![0; n_tokens]);
+            assert_eq!(
+                last_layer.token_indices,
+                (0..n_tokens as u32).collect::<Vec<_>>()
+            );
+            assert_eq!(last_layer.data.len(), n_tokens * last_layer.dims as usize);
+
+            // A mix of the embedding layer and the last layer.
+            let mix = embed(
+                EmbedOptions {
+                    layers: vec![0, -1],
+                    weights: vec![0., 1.],
+                    ..Default::default()
+                },
+                &mut err,
+            )
+            .unwrap();
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            assert_eq!(mix.dims, last_layer.dims);
+            for (&mixed, &last) in mix.data.iter().zip(&last_layer.data) {
+                assert!((mixed - last).abs() < 1e-5);
+            }
+
+            let _ = embed(
+                EmbedOptions {
+                    layers: vec![1000],
+                    ..Default::default()
+                },
+                &mut err,
+            );
+            assert_eq!(err.get_code(), ErrorCode::new(INVALID_OPTION_ERROR));
+        });
+    }
+
+    #[test]
+    fn model_gives_sentence_embeddings() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+            let options_proto = Vec::new();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_embed(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let token_embeddings = Embeddings::decode(buffer.as_slice()).unwrap();
+
+            // Mean pooling is the default.
+            let buffer = unsafe {
+                syntaxdot_annotator_embed_sentences(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    options_proto.as_ptr(),
+                    options_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let sentence_embeddings = SentenceEmbeddings::decode(buffer.as_slice()).unwrap();
+
+            assert_eq!(sentence_embeddings.n_sentences, 1);
+            assert_eq!(sentence_embeddings.dims, token_embeddings.dims);
+
+            let dims = token_embeddings.dims as usize;
+            let n_tokens = token_embeddings.token_indices.len();
+            for (dim, &pooled) in sentence_embeddings.data.iter().enumerate() {
+                let mean = token_embeddings
+                    .data
+                    .iter()
+                    .skip(dim)
+                    .step_by(dims)
+                    .sum::<f32>()
+                    / n_tokens as f32;
+                assert!((pooled - mean).abs() < 1e-4);
+            }
+        });
+    }
+
+    #[test]
+    fn model_gives_pieces() {
+        with_test_model(|handle| {
+            let mut err = ExternError::default();
+
+            let sentences_proto = test_sentence_protobuf();
+
+            let buffer = unsafe {
+                syntaxdot_annotator_tokenize(
+                    handle,
+                    sentences_proto.as_ptr(),
+                    sentences_proto.len() as i32,
+                    &mut err,
+                )
+            };
+            assert_eq!(err.get_code(), ErrorCode::SUCCESS);
+            let tokenized = TokenizedSentences::decode(buffer.as_slice()).unwrap();
+
+            assert_eq!(tokenized.sentences.len(), 1);
+            let sentence = &tokenized.sentences[0];
+            assert_eq!(sentence.pieces.len(), sentence.piece_ids.len());
+            assert_eq!(sentence.token_offsets.len(), 5);
+            assert!(sentence.pieces.iter().all(|piece| !piece.is_empty()));
+
+            // The first piece is the special piece that represents the root.
+            assert_eq!(sentence.token_offsets[0], 1);
+            assert!(sentence
+                .token_offsets
+                .windows(2)
+                .all(|offsets| offsets[0] < offsets[1]));
+        });
+    }
 }
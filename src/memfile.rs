@@ -0,0 +1,112 @@
+//! Anonymous in-memory files.
+//!
+//! tch can only load parameters from a path. To load parameters that
+//! are in memory without writing them to disk, they are written to an
+//! anonymous in-memory file, which is then loaded through its path in
+//! `/proc/self/fd`. Such files are only available on Linux.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::AnnotatorError;
+
+/// An anonymous file that only exists in memory.
+pub struct MemFile {
+    #[cfg(target_os = "linux")]
+    file: std::fs::File,
+
+    path: PathBuf,
+}
+
+impl MemFile {
+    /// Create an empty in-memory file.
+    ///
+    /// The name is only used for debugging purposes.
+    #[cfg(target_os = "linux")]
+    pub fn new(name: &str) -> Result<Self, AnnotatorError> {
+        use std::ffi::CString;
+        use std::fs::File;
+        use std::os::unix::io::FromRawFd;
+
+        let c_name = CString::new(name).map_err(|err| {
+            AnnotatorError::Io(
+                "Cannot create in-memory file".to_string(),
+                io::Error::new(io::ErrorKind::InvalidInput, err),
+            )
+        })?;
+
+        // Safety: c_name is a valid NUL-terminated string.
+        let fd = unsafe { libc::memfd_create(c_name.as_ptr(), libc::MFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(AnnotatorError::Io(
+                "Cannot create in-memory file".to_string(),
+                io::Error::last_os_error(),
+            ));
+        }
+
+        // Safety: fd is a newly-created descriptor that is owned by the file.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        Ok(MemFile {
+            file,
+            path: PathBuf::from(format!("/proc/self/fd/{}", fd)),
+        })
+    }
+
+    /// Create an empty in-memory file.
+    ///
+    /// Always fails, since in-memory files are only supported on Linux.
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_name: &str) -> Result<Self, AnnotatorError> {
+        Err(AnnotatorError::Unsupported(
+            "loading model parameters from memory is only supported on Linux".to_string(),
+        ))
+    }
+
+    /// The path through which the file can be opened.
+    ///
+    /// The path is only valid in this process and while the file is
+    /// not dropped.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Write for MemFile {
+    #[cfg(target_os = "linux")]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        unreachable!("in-memory files cannot be constructed on this platform")
+    }
+
+    #[cfg(target_os = "linux")]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn flush(&mut self) -> io::Result<()> {
+        unreachable!("in-memory files cannot be constructed on this platform")
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+
+    use super::MemFile;
+
+    #[test]
+    fn memfile_can_be_read_through_its_path() {
+        let mut file = MemFile::new("test").unwrap();
+        file.write_all(b"parameters").unwrap();
+        file.flush().unwrap();
+
+        assert_eq!(fs::read(file.path()).unwrap(), b"parameters");
+    }
+}
@@ -0,0 +1,568 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use tch::{Cuda, Device};
+
+use crate::tagger::{HEAD, RELATION};
+use crate::AnnotatorError;
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/syntaxdot.options.rs"));
+}
+
+/// The batch size that is used when no batch size is specified.
+pub const DEFAULT_BATCH_SIZE: usize = 32;
+
+/// Annotator options.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AnnotatorOptions {
+    /// The device to run the model on.
+    pub device: Device,
+
+    /// Batch size to use when an annotate call does not specify one.
+    pub batch_size: usize,
+
+    /// The maximum number of word pieces in a sentence.
+    ///
+    /// The maximum sequence length of the model is used when this is
+    /// `None` or larger.
+    pub max_len: Option<usize>,
+
+    /// The number of Torch inter-op threads.
+    pub num_interop_threads: Option<i32>,
+
+    /// The number of Torch intra-op threads.
+    pub num_intraop_threads: Option<i32>,
+
+    /// The sequence labeling encoders to load, all encoders if `None`.
+    pub encoders: Option<HashSet<String>>,
+
+    /// Load the biaffine dependency parser.
+    pub biaffine: bool,
+}
+
+impl AnnotatorOptions {
+    /// Check that the thread settings can be applied.
+    ///
+    /// Torch thread pools are global, so changing them would affect the
+    /// `n_loaded` annotators that are already loaded. Thread settings are
+    /// rejected when any annotator is loaded.
+    pub fn check_num_threads(&self, n_loaded: usize) -> Result<(), AnnotatorError> {
+        if (self.num_interop_threads.is_some() || self.num_intraop_threads.is_some())
+            && n_loaded != 0
+        {
+            return Err(AnnotatorError::InvalidOption(format!(
+                "thread settings cannot be changed while {} annotator(s) are loaded",
+                n_loaded
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the thread settings.
+    ///
+    /// Settings are only applied when they were checked with
+    /// [`AnnotatorOptions::check_num_threads`].
+    pub fn set_num_threads(&self) {
+        if let Some(n_threads) = self.num_interop_threads {
+            tch::set_num_interop_threads(n_threads);
+        }
+
+        if let Some(n_threads) = self.num_intraop_threads {
+            tch::set_num_threads(n_threads);
+        }
+    }
+}
+
+impl Default for AnnotatorOptions {
+    fn default() -> Self {
+        AnnotatorOptions {
+            device: Device::Cpu,
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_len: None,
+            num_interop_threads: None,
+            num_intraop_threads: None,
+            encoders: None,
+            biaffine: true,
+        }
+    }
+}
+
+impl TryFrom<proto::AnnotatorOptions> for AnnotatorOptions {
+    type Error = AnnotatorError;
+
+    fn try_from(options: proto::AnnotatorOptions) -> Result<Self, Self::Error> {
+        let device = if options.device.is_empty() {
+            Device::Cpu
+        } else {
+            parse_device(&options.device)?
+        };
+
+        let batch_size = if options.batch_size == 0 {
+            DEFAULT_BATCH_SIZE
+        } else {
+            options.batch_size as usize
+        };
+
+        let max_len = if options.max_len == 0 {
+            None
+        } else {
+            Some(options.max_len as usize)
+        };
+
+        let encoders = if options.encoders.is_empty() {
+            None
+        } else {
+            Some(options.encoders.into_iter().collect())
+        };
+
+        Ok(AnnotatorOptions {
+            device,
+            batch_size,
+            max_len,
+            num_interop_threads: n_threads("num_interop_threads", options.num_interop_threads)?,
+            num_intraop_threads: n_threads("num_intraop_threads", options.num_intraop_threads)?,
+            encoders,
+            biaffine: !options.disable_biaffine,
+        })
+    }
+}
+
+/// Options for an annotate call.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AnnotateOptions {
+    /// Model batch size, 0 to use the default batch size of the annotator.
+    pub batch_size: usize,
+
+    /// Compute the probability of the label that is chosen for each layer.
+    pub confidences: bool,
+
+    /// The number of most probable labels to return per layer.
+    pub top_k: HashMap<String, usize>,
+
+    /// The head scores of the biaffine parser to return.
+    pub head_scores: HeadScores,
+
+    /// Annotation policies of layers, layers without a policy are overwritten.
+    pub policies: HashMap<String, AnnotationPolicy>,
+
+    /// The layers to annotate, all layers if `None`.
+    pub layers: Option<HashSet<String>>,
+
+    /// How sentences that exceed the maximum sequence length are handled.
+    pub long_sentences: LongSentences,
+
+    /// The number of pieces that consecutive windows share, half of the
+    /// maximum sequence length if `None`.
+    pub window_overlap: Option<usize>,
+
+    /// Report errors per sentence rather than failing the annotate call.
+    pub per_sentence_errors: bool,
+
+    /// The maximum number of pieces in a batch, including padding. No
+    /// limit if `None`.
+    pub max_batch_pieces: Option<usize>,
+}
+
+impl AnnotateOptions {
+    /// Check whether any token label scores were requested.
+    pub fn label_scores_requested(&self) -> bool {
+        self.confidences || self.top_k.values().any(|&k| k > 0)
+    }
+
+    /// Check whether a layer should be annotated.
+    pub fn layer_enabled(&self, layer: &str) -> bool {
+        self.layers
+            .as_ref()
+            .map(|layers| layers.contains(layer))
+            .unwrap_or(true)
+    }
+
+    /// Get the annotation policy of a layer.
+    ///
+    /// Layers that are not enabled are skipped.
+    pub fn policy(&self, layer: &str) -> AnnotationPolicy {
+        if !self.layer_enabled(layer) {
+            return AnnotationPolicy::Skip;
+        }
+
+        self.policies.get(layer).copied().unwrap_or_default()
+    }
+}
+
+impl TryFrom<proto::AnnotateOptions> for AnnotateOptions {
+    type Error = AnnotatorError;
+
+    fn try_from(options: proto::AnnotateOptions) -> Result<Self, Self::Error> {
+        let head_scores = match proto::HeadScores::from_i32(options.head_scores) {
+            Some(proto::HeadScores::NoHeadScores) => HeadScores::None,
+            Some(proto::HeadScores::HeadScoreLogits) => HeadScores::Logits,
+            Some(proto::HeadScores::HeadProbabilities) => HeadScores::Probabilities,
+            None => {
+                return Err(AnnotatorError::InvalidOption(format!(
+                    "unknown head scores type: {}",
+                    options.head_scores
+                )))
+            }
+        };
+
+        let policies = options
+            .policies
+            .into_iter()
+            .map(|(layer, policy)| {
+                let policy = match proto::AnnotationPolicy::from_i32(policy) {
+                    Some(proto::AnnotationPolicy::Overwrite) => AnnotationPolicy::Overwrite,
+                    Some(proto::AnnotationPolicy::KeepIfPresent) => AnnotationPolicy::KeepIfPresent,
+                    Some(proto::AnnotationPolicy::Skip) => AnnotationPolicy::Skip,
+                    None => {
+                        return Err(AnnotatorError::InvalidOption(format!(
+                            "unknown annotation policy for layer {}: {}",
+                            layer, policy
+                        )))
+                    }
+                };
+
+                Ok((layer, policy))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let layers: Option<HashSet<String>> = if options.layers.is_empty() {
+            None
+        } else {
+            Some(options.layers.into_iter().collect())
+        };
+
+        // The biaffine parser assigns the head and relation together.
+        if let Some(layers) = &layers {
+            if layers.contains(HEAD) != layers.contains(RELATION) {
+                return Err(AnnotatorError::InvalidOption(
+                    "head and relation can only be selected together".to_string(),
+                ));
+            }
+        }
+
+        let long_sentences = match proto::LongSentences::from_i32(options.long_sentences) {
+            Some(proto::LongSentences::RejectLongSentences) => LongSentences::Reject,
+            Some(proto::LongSentences::TruncateLongSentences) => LongSentences::Truncate,
+            Some(proto::LongSentences::SlidingWindow) => LongSentences::SlidingWindow,
+            None => {
+                return Err(AnnotatorError::InvalidOption(format!(
+                    "unknown long sentence handling: {}",
+                    options.long_sentences
+                )))
+            }
+        };
+
+        let max_batch_pieces = if options.max_batch_pieces == 0 {
+            None
+        } else {
+            Some(options.max_batch_pieces as usize)
+        };
+
+        let window_overlap = if options.window_overlap == 0 {
+            None
+        } else {
+            Some(options.window_overlap as usize)
+        };
+
+        Ok(AnnotateOptions {
+            batch_size: options.batch_size as usize,
+            confidences: options.confidences,
+            top_k: options
+                .top_k
+                .into_iter()
+                .map(|(layer, k)| (layer, k as usize))
+                .collect(),
+            head_scores,
+            policies,
+            layers,
+            long_sentences,
+            window_overlap,
+            per_sentence_errors: options.per_sentence_errors,
+            max_batch_pieces,
+        })
+    }
+}
+
+/// Options for an embed call.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EmbedOptions {
+    /// Model batch size, 0 to use the default batch size of the annotator.
+    pub batch_size: usize,
+
+    /// The layers to combine, negative indices count from the last layer.
+    pub layers: Vec<isize>,
+
+    /// The weights of the layers, the mean of the layers is used when empty.
+    pub weights: Vec<f32>,
+
+    /// The pooling method for sentence embeddings.
+    pub pooling: SentencePooling,
+}
+
+impl TryFrom<proto::EmbedOptions> for EmbedOptions {
+    type Error = AnnotatorError;
+
+    fn try_from(options: proto::EmbedOptions) -> Result<Self, Self::Error> {
+        if !options.weights.is_empty() && options.weights.len() != options.layers.len() {
+            return Err(AnnotatorError::InvalidOption(format!(
+                "got {} layer weights for {} layers",
+                options.weights.len(),
+                options.layers.len()
+            )));
+        }
+
+        let pooling = match proto::SentencePooling::from_i32(options.pooling) {
+            Some(proto::SentencePooling::MeanPooling) => SentencePooling::Mean,
+            Some(proto::SentencePooling::MaxPooling) => SentencePooling::Max,
+            Some(proto::SentencePooling::ClsPooling) => SentencePooling::Cls,
+            None => {
+                return Err(AnnotatorError::InvalidOption(format!(
+                    "unknown sentence pooling: {}",
+                    options.pooling
+                )))
+            }
+        };
+
+        Ok(EmbedOptions {
+            batch_size: options.batch_size as usize,
+            layers: options
+                .layers
+                .into_iter()
+                .map(|layer| layer as isize)
+                .collect(),
+            weights: options.weights,
+            pooling,
+        })
+    }
+}
+
+/// Pooling methods for sentence embeddings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SentencePooling {
+    /// The mean of the token embeddings.
+    Mean,
+
+    /// The element-wise maximum of the token embeddings.
+    Max,
+
+    /// The embedding of the first piece of the sentence.
+    Cls,
+}
+
+impl Default for SentencePooling {
+    fn default() -> Self {
+        SentencePooling::Mean
+    }
+}
+
+/// Head scores of the biaffine parser.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HeadScores {
+    /// Do not return head scores.
+    None,
+
+    /// Return the head score logits.
+    Logits,
+
+    /// Return the probability distributions over the heads of tokens.
+    Probabilities,
+}
+
+impl Default for HeadScores {
+    fn default() -> Self {
+        HeadScores::None
+    }
+}
+
+/// How a layer is annotated.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnnotationPolicy {
+    /// Replace the values of the layer by the predicted values.
+    Overwrite,
+
+    /// Only predict the values of tokens that do not have a value.
+    KeepIfPresent,
+
+    /// Do not annotate the layer.
+    Skip,
+}
+
+impl Default for AnnotationPolicy {
+    fn default() -> Self {
+        AnnotationPolicy::Overwrite
+    }
+}
+
+/// Handling of sentences that exceed the maximum sequence length.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LongSentences {
+    /// Fail with an error.
+    Reject,
+
+    /// Only annotate the tokens that fit in the maximum sequence length.
+    Truncate,
+
+    /// Encode the sentence in overlapping windows.
+    SlidingWindow,
+}
+
+impl Default for LongSentences {
+    fn default() -> Self {
+        LongSentences::Reject
+    }
+}
+
+fn n_threads(option: &str, n_threads: i32) -> Result<Option<i32>, AnnotatorError> {
+    match n_threads {
+        0 => Ok(None),
+        n if n > 0 => Ok(Some(n)),
+        n => Err(AnnotatorError::InvalidOption(format!(
+            "{} should not be negative, was: {}",
+            option, n
+        ))),
+    }
+}
+
+/// Parse a device string, such as `cpu` or `cuda:1`.
+fn parse_device(device: &str) -> Result<Device, AnnotatorError> {
+    let (name, index) = match device.find(':') {
+        Some(idx) => (&device[..idx], Some(&device[idx + 1..])),
+        None => (device, None),
+    };
+
+    match (name, index) {
+        ("cpu", None) => Ok(Device::Cpu),
+        ("cuda", index) => {
+            let index = index
+                .map(|index| index.parse::<usize>())
+                .transpose()
+                .map_err(|_| AnnotatorError::InvalidDevice(device.to_string()))?
+                .unwrap_or(0);
+
+            if !Cuda::is_available() || index as i64 >= Cuda::device_count() {
+                return Err(AnnotatorError::InvalidDevice(device.to_string()));
+            }
+
+            Ok(Device::Cuda(index))
+        }
+        _ => Err(AnnotatorError::InvalidDevice(device.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use ffi_support::ErrorCode;
+
+    use super::{proto, AnnotateOptions, AnnotationPolicy, AnnotatorOptions, EmbedOptions};
+    use crate::error::error_codes::{INVALID_DEVICE_ERROR, INVALID_OPTION_ERROR};
+
+    #[test]
+    fn embed_options_with_mismatching_weights_are_rejected() {
+        let err = EmbedOptions::try_from(proto::EmbedOptions {
+            layers: vec![-1, -2],
+            weights: vec![1.],
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+    }
+
+    #[test]
+    fn options_with_invalid_head_scores_are_rejected() {
+        let err = AnnotateOptions::try_from(proto::AnnotateOptions {
+            head_scores: 42,
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+    }
+
+    #[test]
+    fn layers_that_are_not_selected_are_skipped() {
+        let options = AnnotateOptions::try_from(proto::AnnotateOptions {
+            policies: vec![(
+                "upos".to_string(),
+                proto::AnnotationPolicy::KeepIfPresent as i32,
+            )]
+            .into_iter()
+            .collect(),
+            layers: vec!["upos".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(options.policy("upos"), AnnotationPolicy::KeepIfPresent);
+        assert_eq!(options.policy("lemma"), AnnotationPolicy::Skip);
+
+        // All layers are annotated by default.
+        let options = AnnotateOptions::try_from(proto::AnnotateOptions::default()).unwrap();
+        assert_eq!(options.policy("lemma"), AnnotationPolicy::Overwrite);
+    }
+
+    #[test]
+    fn head_and_relation_are_selected_together() {
+        for layers in &[vec!["upos", "head"], vec!["relation"]] {
+            let err = AnnotateOptions::try_from(proto::AnnotateOptions {
+                layers: layers.iter().map(ToString::to_string).collect(),
+                ..Default::default()
+            })
+            .unwrap_err();
+            assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+        }
+
+        let options = AnnotateOptions::try_from(proto::AnnotateOptions {
+            layers: vec!["head".to_string(), "relation".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(options.policy("head"), AnnotationPolicy::Overwrite);
+        assert_eq!(options.policy("relation"), AnnotationPolicy::Overwrite);
+    }
+
+    #[test]
+    fn options_with_invalid_annotation_policy_are_rejected() {
+        let err = AnnotateOptions::try_from(proto::AnnotateOptions {
+            policies: vec![("upos".to_string(), 42)].into_iter().collect(),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+    }
+
+    #[test]
+    fn options_with_invalid_device_are_rejected() {
+        let err = AnnotatorOptions::try_from(proto::AnnotatorOptions {
+            device: "tpu".to_string(),
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_DEVICE_ERROR));
+    }
+
+    #[test]
+    fn options_with_negative_threads_are_rejected() {
+        let err = AnnotatorOptions::try_from(proto::AnnotatorOptions {
+            num_intraop_threads: -1,
+            ..Default::default()
+        })
+        .unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+    }
+
+    #[test]
+    fn thread_options_are_rejected_when_annotators_are_loaded() {
+        let options = AnnotatorOptions {
+            num_intraop_threads: Some(2),
+            ..Default::default()
+        };
+        let err = options.check_num_threads(1).unwrap_err();
+        assert_eq!(ErrorCode::from(&err), ErrorCode::new(INVALID_OPTION_ERROR));
+        assert!(options.check_num_threads(0).is_ok());
+
+        // Without thread options, nothing is changed.
+        assert!(AnnotatorOptions::default().check_num_threads(1).is_ok());
+    }
+}
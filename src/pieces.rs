@@ -0,0 +1,173 @@
+//! Word pieces of sentences.
+
+use std::io::BufRead;
+
+use ffi_support::implement_into_ffi_by_protobuf;
+use syntaxdot::config::Tokenizer;
+use syntaxdot_tokenizers::SentenceWithPieces;
+
+use crate::AnnotatorError;
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/syntaxdot.pieces.rs"));
+}
+
+mod sentencepiece_proto {
+    include!(concat!(env!("OUT_DIR"), "/sentencepiece.rs"));
+}
+
+/// XLM-RoBERTa beginning of sentence piece, its identifier is 0.
+const FAIRSEQ_BOS_PIECE: &str = "<s>";
+
+const FAIRSEQ_EOS_ID: usize = 2;
+const FAIRSEQ_EOS_PIECE: &str = "</s>";
+
+/// Vocabulary for looking up the pieces of piece identifiers.
+pub struct PieceVocab {
+    pieces: Vec<String>,
+}
+
+impl PieceVocab {
+    /// Read the piece vocabulary of a tokenizer.
+    ///
+    /// `data` must be the contents of the vocabulary file of the
+    /// tokenizer: the word pieces of a BERT tokenizer or the
+    /// sentencepiece model of an ALBERT or XLM-RoBERTa tokenizer.
+    pub fn read(tokenizer: &Tokenizer, data: &[u8]) -> Result<Self, AnnotatorError> {
+        let pieces = match tokenizer {
+            Tokenizer::Albert { .. } => read_sentencepiece_pieces(data)?,
+            Tokenizer::Bert { .. } => data.lines().collect::<Result<_, _>>().map_err(|err| {
+                AnnotatorError::Io("Cannot read BERT vocabulary".to_string(), err)
+            })?,
+            Tokenizer::XlmRoberta { .. } => {
+                // The XLM-RoBERTa tokenizer uses fairseq identifiers,
+                // which are sentencepiece identifiers shifted by one,
+                // with their own beginning/end of sentence markers.
+                let mut pieces = vec![FAIRSEQ_BOS_PIECE.to_string()];
+                pieces.extend(read_sentencepiece_pieces(data)?);
+                if let Some(eos) = pieces.get_mut(FAIRSEQ_EOS_ID) {
+                    *eos = FAIRSEQ_EOS_PIECE.to_string();
+                }
+                pieces
+            }
+        };
+
+        Ok(PieceVocab { pieces })
+    }
+
+    /// Get the piece with the given identifier.
+    pub fn piece(&self, id: i64) -> Option<&str> {
+        if id < 0 {
+            return None;
+        }
+
+        self.pieces.get(id as usize).map(String::as_str)
+    }
+
+    /// Convert a sentence with pieces to its protobuf representation.
+    pub fn tokenized_sentence(&self, sentence: &SentenceWithPieces) -> proto::TokenizedSentence {
+        proto::TokenizedSentence {
+            piece_ids: sentence.pieces.to_vec(),
+            pieces: sentence
+                .pieces
+                .iter()
+                .map(|&id| self.piece(id).unwrap_or_default().to_string())
+                .collect(),
+            token_offsets: sentence
+                .token_offsets
+                .iter()
+                .map(|&offset| offset as u32)
+                .collect(),
+        }
+    }
+}
+
+/// Read the pieces of a serialized sentencepiece model.
+fn read_sentencepiece_pieces(data: &[u8]) -> Result<Vec<String>, AnnotatorError> {
+    let model: sentencepiece_proto::ModelProto = prost::Message::decode(data)?;
+    Ok(model
+        .pieces
+        .into_iter()
+        .map(|piece| piece.piece.unwrap_or_default())
+        .collect())
+}
+
+implement_into_ffi_by_protobuf!(proto::TokenizedSentences);
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use ndarray::array;
+    use prost::Message;
+    use syntaxdot::config::Tokenizer;
+    use syntaxdot_tokenizers::SentenceWithPieces;
+    use udgraph::graph::Sentence;
+    use udgraph::token::Token;
+
+    use super::{proto, sentencepiece_proto, PieceVocab};
+
+    #[test]
+    fn pieces_are_looked_up_in_vocab() {
+        let vocab = PieceVocab::read(
+            &Tokenizer::Bert {
+                vocab: "vocab.txt".to_string(),
+            },
+            b"[CLS]\n[UNK]\nSpenden\n##geld\ndie\n",
+        )
+        .unwrap();
+
+        let sentence = SentenceWithPieces {
+            pieces: array![0, 4, 2, 3, 1],
+            sentence: Sentence::from_iter(vec![
+                Token::new("die"),
+                Token::new("Spendengeld"),
+                Token::new("?"),
+            ]),
+            token_offsets: vec![1, 2, 4],
+        };
+
+        assert_eq!(
+            vocab.tokenized_sentence(&sentence),
+            proto::TokenizedSentence {
+                piece_ids: vec![0, 4, 2, 3, 1],
+                pieces: vec![
+                    "[CLS]".to_string(),
+                    "die".to_string(),
+                    "Spenden".to_string(),
+                    "##geld".to_string(),
+                    "[UNK]".to_string()
+                ],
+                token_offsets: vec![1, 2, 4],
+            }
+        );
+    }
+
+    #[test]
+    fn xlm_roberta_pieces_are_shifted() {
+        let model = sentencepiece_proto::ModelProto {
+            pieces: ["<unk>", "<s>", "</s>", "die"]
+                .iter()
+                .map(|piece| sentencepiece_proto::model_proto::SentencePiece {
+                    piece: Some(piece.to_string()),
+                })
+                .collect(),
+        };
+        let mut data = Vec::new();
+        model.encode(&mut data).unwrap();
+
+        let vocab = PieceVocab::read(
+            &Tokenizer::XlmRoberta {
+                vocab: "sentencepiece.model".to_string(),
+            },
+            &data,
+        )
+        .unwrap();
+
+        assert_eq!(vocab.piece(0), Some("<s>"));
+        assert_eq!(vocab.piece(1), Some("<unk>"));
+        assert_eq!(vocab.piece(2), Some("</s>"));
+        assert_eq!(vocab.piece(4), Some("die"));
+        assert_eq!(vocab.piece(5), None);
+    }
+}
@@ -0,0 +1,390 @@
+//! Rule-based sentence splitting and word tokenization.
+//!
+//! A [`Segmenter`] splits raw text into `udgraph` sentences, using a
+//! [`WordTokenizer`] to find the tokens and a [`SentenceSplitter`] to
+//! group the tokens into sentences. Both are traits, so that other
+//! tokenizers and sentence splitters can be plugged in.
+//!
+//! The position of every token in the input text is recorded in the
+//! MISC field as `TokenRange=start:end`, where `start` and `end` are
+//! character (Unicode scalar value) offsets and `end` is exclusive.
+//! Tokens that are not followed by whitespace get `SpaceAfter=No`.
+
+use std::collections::HashSet;
+use std::iter;
+use std::ops::Range;
+
+use udgraph::graph::Sentence;
+use udgraph::token::Token;
+
+use crate::sentences::{SPACE_AFTER, TOKEN_RANGE};
+
+pub mod proto {
+    include!(concat!(env!("OUT_DIR"), "/syntaxdot.segmenter.rs"));
+}
+
+/// A token in the input text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TextToken {
+    /// The form of the token.
+    pub form: String,
+
+    /// Character offset of the start of the token.
+    pub start: usize,
+
+    /// Character offset of the end of the token (exclusive).
+    pub end: usize,
+}
+
+/// Split text into tokens.
+pub trait WordTokenizer {
+    /// Tokenize the text.
+    ///
+    /// Tokens must be returned in text order and must not overlap.
+    fn tokenize(&self, text: &str) -> Vec<TextToken>;
+}
+
+/// Group tokens into sentences.
+pub trait SentenceSplitter {
+    /// Split the tokens of `text` into sentences.
+    ///
+    /// Returns the token index range of every sentence. The ranges must
+    /// cover all tokens in order.
+    fn split(&self, text: &str, tokens: &[TextToken]) -> Vec<Range<usize>>;
+}
+
+/// Sentence splitter and word tokenizer.
+pub struct Segmenter {
+    tokenizer: Box<dyn WordTokenizer>,
+    splitter: Box<dyn SentenceSplitter>,
+}
+
+impl Segmenter {
+    /// Construct a segmenter from a word tokenizer and a sentence splitter.
+    pub fn new(
+        tokenizer: impl WordTokenizer + 'static,
+        splitter: impl SentenceSplitter + 'static,
+    ) -> Self {
+        Segmenter {
+            tokenizer: Box::new(tokenizer),
+            splitter: Box::new(splitter),
+        }
+    }
+
+    /// Split text into sentences.
+    pub fn segment(&self, text: &str) -> Vec<Sentence> {
+        let tokens = self.tokenizer.tokenize(text);
+
+        self.splitter
+            .split(text, &tokens)
+            .into_iter()
+            .map(|range| {
+                range
+                    .map(|idx| {
+                        let text_token = &tokens[idx];
+                        let mut token = Token::new(text_token.form.clone());
+
+                        let misc = token.misc_mut();
+                        misc.insert(
+                            TOKEN_RANGE.to_string(),
+                            Some(format!("{}:{}", text_token.start, text_token.end)),
+                        );
+                        if let Some(next) = tokens.get(idx + 1) {
+                            if next.start == text_token.end {
+                                misc.insert(SPACE_AFTER.to_string(), Some("No".to_string()));
+                            }
+                        }
+
+                        token
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Default for Segmenter {
+    fn default() -> Self {
+        Segmenter::new(
+            RuleBasedTokenizer::default(),
+            RuleBasedSentenceSplitter::default(),
+        )
+    }
+}
+
+impl From<proto::SegmenterOptions> for Segmenter {
+    fn from(options: proto::SegmenterOptions) -> Self {
+        let tokenizer = RuleBasedTokenizer::new(options.abbreviations);
+
+        if options.disable_sentence_splitting {
+            Segmenter::new(tokenizer, NoSentenceSplitter)
+        } else {
+            Segmenter::new(
+                tokenizer,
+                RuleBasedSentenceSplitter::new(options.newline_boundaries),
+            )
+        }
+    }
+}
+
+/// Rule-based word tokenizer.
+///
+/// Tokens are separated by whitespace. A token is either a word, a run
+/// of letters and digits, or a punctuation token. Words can contain a
+/// hyphen, apostrophe or period between letters and digits (*e-mail*,
+/// *3.14*), and a comma between digits (*2,5*). Punctuation tokens
+/// consist of a single character or a run of the same character (*...*).
+///
+/// A word keeps a following period when the word is an abbreviation or
+/// contains periods itself (*U.S.*).
+#[derive(Clone, Debug, Default)]
+pub struct RuleBasedTokenizer {
+    abbreviations: HashSet<String>,
+}
+
+impl RuleBasedTokenizer {
+    /// Construct a tokenizer with the given abbreviations.
+    ///
+    /// Abbreviations are matched case-insensitively, a trailing period
+    /// in the given abbreviations is ignored.
+    pub fn new(abbreviations: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        RuleBasedTokenizer {
+            abbreviations: abbreviations
+                .into_iter()
+                .map(|abbr| abbr.as_ref().trim_end_matches('.').to_lowercase())
+                .collect(),
+        }
+    }
+
+    fn keeps_period(&self, word: &[char]) -> bool {
+        let word = word.iter().collect::<String>();
+        word.contains('.') || self.abbreviations.contains(&word.to_lowercase())
+    }
+}
+
+impl WordTokenizer for RuleBasedTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<TextToken> {
+        let chars = text.chars().collect::<Vec<_>>();
+
+        let mut tokens = Vec::new();
+        let mut idx = 0;
+        while idx < chars.len() {
+            let c = chars[idx];
+            if c.is_whitespace() {
+                idx += 1;
+                continue;
+            }
+
+            let start = idx;
+            idx += 1;
+
+            if c.is_alphanumeric() {
+                while idx < chars.len() {
+                    if chars[idx].is_alphanumeric() {
+                        idx += 1;
+                    } else if idx + 1 < chars.len()
+                        && is_word_internal(chars[idx - 1], chars[idx], chars[idx + 1])
+                    {
+                        idx += 2;
+                    } else {
+                        break;
+                    }
+                }
+
+                if idx < chars.len() && chars[idx] == '.' && self.keeps_period(&chars[start..idx]) {
+                    idx += 1;
+                }
+            } else {
+                while idx < chars.len() && chars[idx] == c {
+                    idx += 1;
+                }
+            }
+
+            tokens.push(TextToken {
+                form: chars[start..idx].iter().collect(),
+                start,
+                end: idx,
+            });
+        }
+
+        tokens
+    }
+}
+
+fn is_word_internal(prev: char, c: char, next: char) -> bool {
+    match c {
+        '-' | '\'' | '’' | '.' => prev.is_alphanumeric() && next.is_alphanumeric(),
+        ',' => prev.is_numeric() && next.is_numeric(),
+        _ => false,
+    }
+}
+
+/// Rule-based sentence splitter.
+///
+/// A sentence ends after a token that consists of sentence-final
+/// punctuation (`.`, `!`, `?`, `…`), including any closing quotes or
+/// brackets that are directly attached to it. Empty lines always end a
+/// sentence.
+#[derive(Clone, Debug, Default)]
+pub struct RuleBasedSentenceSplitter {
+    newline_boundaries: bool,
+}
+
+impl RuleBasedSentenceSplitter {
+    /// Construct a sentence splitter.
+    ///
+    /// If `newline_boundaries` is `true`, every line break ends a sentence.
+    pub fn new(newline_boundaries: bool) -> Self {
+        RuleBasedSentenceSplitter { newline_boundaries }
+    }
+
+    fn is_boundary(&self, chars: &[char], tokens: &[TextToken], idx: usize) -> bool {
+        let next = match tokens.get(idx + 1) {
+            Some(next) => next,
+            None => return true,
+        };
+
+        let newlines = chars[tokens[idx].end..next.start]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        if newlines > 1 || (self.newline_boundaries && newlines == 1) {
+            return true;
+        }
+
+        // Closing quotes and brackets that are attached to sentence-final
+        // punctuation belong to the same sentence.
+        if next.start == tokens[idx].end && is_closing(&next.form) {
+            return false;
+        }
+
+        let mut final_idx = idx;
+        while final_idx > 0
+            && is_closing(&tokens[final_idx].form)
+            && tokens[final_idx - 1].end == tokens[final_idx].start
+        {
+            final_idx -= 1;
+        }
+
+        is_sentence_final(&tokens[final_idx].form)
+    }
+}
+
+impl SentenceSplitter for RuleBasedSentenceSplitter {
+    fn split(&self, text: &str, tokens: &[TextToken]) -> Vec<Range<usize>> {
+        let chars = text.chars().collect::<Vec<_>>();
+
+        let mut sentences = Vec::new();
+        let mut start = 0;
+        for idx in 0..tokens.len() {
+            if self.is_boundary(&chars, tokens, idx) {
+                sentences.push(start..idx + 1);
+                start = idx + 1;
+            }
+        }
+
+        sentences
+    }
+}
+
+fn is_sentence_final(form: &str) -> bool {
+    form.chars().all(|c| matches!(c, '.' | '!' | '?' | '…'))
+}
+
+fn is_closing(form: &str) -> bool {
+    form.chars()
+        .all(|c| matches!(c, '"' | '\'' | ')' | ']' | '}' | '»' | '”' | '’'))
+}
+
+/// Sentence splitter that puts all tokens in a single sentence.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoSentenceSplitter;
+
+impl SentenceSplitter for NoSentenceSplitter {
+    fn split(&self, _text: &str, tokens: &[TextToken]) -> Vec<Range<usize>> {
+        if tokens.is_empty() {
+            Vec::new()
+        } else {
+            iter::once(0..tokens.len()).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use udgraph::graph::Sentence;
+    use udgraph::token::Tokens;
+
+    use super::{proto, RuleBasedTokenizer, Segmenter, WordTokenizer};
+
+    fn forms(sentence: &Sentence) -> Vec<&str> {
+        sentence.tokens().map(|token| token.form()).collect()
+    }
+
+    fn misc_value<'a>(sentence: &'a Sentence, idx: usize, key: &str) -> Option<&'a str> {
+        sentence
+            .tokens()
+            .nth(idx)
+            .unwrap()
+            .misc()
+            .get(key)
+            .and_then(|v| v.as_deref())
+    }
+
+    #[test]
+    fn tokenizer_splits_punctuation() {
+        let tokenizer = RuleBasedTokenizer::new(vec!["dr."]);
+        let forms = tokenizer
+            .tokenize("Dr. Jansen's e-mail kost €2,50... (U.S.) Dr.")
+            .into_iter()
+            .map(|token| token.form)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            forms,
+            vec![
+                "Dr.", "Jansen's", "e-mail", "kost", "€", "2,50", "...", "(", "U.S.", ")", "Dr."
+            ]
+        );
+    }
+
+    #[test]
+    fn segmenter_splits_sentences() {
+        let sentences = Segmenter::default().segment("Dit is een test. \"Echt?\" Ja!\n\nNog één");
+        let forms = sentences.iter().map(forms).collect::<Vec<_>>();
+        assert_eq!(
+            forms,
+            vec![
+                vec!["Dit", "is", "een", "test", "."],
+                vec!["\"", "Echt", "?", "\""],
+                vec!["Ja", "!"],
+                vec!["Nog", "één"],
+            ]
+        );
+    }
+
+    #[test]
+    fn segmenter_records_offsets() {
+        let sentences = Segmenter::default().segment("Één test.");
+        assert_eq!(sentences.len(), 1);
+        assert_eq!(misc_value(&sentences[0], 0, "TokenRange"), Some("0:3"));
+        assert_eq!(misc_value(&sentences[0], 1, "TokenRange"), Some("4:8"));
+        assert_eq!(misc_value(&sentences[0], 1, "SpaceAfter"), Some("No"));
+        assert_eq!(misc_value(&sentences[0], 2, "TokenRange"), Some("8:9"));
+        assert_eq!(misc_value(&sentences[0], 2, "SpaceAfter"), None);
+    }
+
+    #[test]
+    fn segmenter_options_are_applied() {
+        let segmenter = Segmenter::from(proto::SegmenterOptions {
+            newline_boundaries: true,
+            ..Default::default()
+        });
+        assert_eq!(segmenter.segment("Eerste regel\ntweede regel").len(), 2);
+
+        let segmenter = Segmenter::from(proto::SegmenterOptions {
+            disable_sentence_splitting: true,
+            ..Default::default()
+        });
+        assert_eq!(segmenter.segment("Zin één. Zin twee.").len(), 1);
+    }
+}
@@ -1,8 +1,25 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::mem;
 use std::ops::Deref;
 
-use ffi_support::{implement_into_ffi_by_delegation, implement_into_ffi_by_protobuf};
-use udgraph::graph::{DepTriple, Sentence};
-use udgraph::token::{Token, Tokens};
+use ffi_support::{implement_into_ffi_by_delegation, implement_into_ffi_by_protobuf, ErrorCode};
+use udgraph::graph::{Comment, DepTriple, Sentence};
+use udgraph::token::{Misc, Token, Tokens};
+
+use crate::constraints::SentenceConstraints;
+use crate::tagger::SentenceScores;
+use crate::AnnotatorError;
+
+/// Comment attribute for sentence identifiers.
+pub const SENT_ID: &str = "sent_id";
+
+/// MISC key for the character range of a token.
+pub const TOKEN_RANGE: &str = "TokenRange";
+
+/// MISC key that marks tokens that are not followed by whitespace.
+pub const SPACE_AFTER: &str = "SpaceAfter";
 
 pub mod proto {
     include!(concat!(env!("OUT_DIR"), "/syntaxdot.sentence.rs"));
@@ -18,8 +35,77 @@ impl Deref for Sentences {
     }
 }
 
+/// A sentence with its multiword tokens, empty nodes, and decoding
+/// constraints.
+///
+/// `udgraph` does not support multiword tokens and empty nodes, so they
+/// are stored alongside the sentence.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedSentence {
+    pub sentence: Sentence,
+    pub multiword_tokens: Vec<MultiwordToken>,
+    pub empty_nodes: Vec<EmptyNode>,
+    pub constraints: SentenceConstraints,
+}
+
+impl From<Sentence> for ExtendedSentence {
+    fn from(sentence: Sentence) -> Self {
+        ExtendedSentence {
+            sentence,
+            multiword_tokens: Vec::new(),
+            empty_nodes: Vec::new(),
+            constraints: SentenceConstraints::default(),
+        }
+    }
+}
+
+/// A multiword token.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MultiwordToken {
+    /// The first token of the multiword token, starting at 1.
+    pub first: usize,
+
+    /// The last token of the multiword token (inclusive).
+    pub last: usize,
+
+    pub form: String,
+
+    pub misc: Misc,
+}
+
+/// An empty node of an enhanced dependency graph.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmptyNode {
+    /// The token that the empty node follows, 0 if the node precedes
+    /// the first token.
+    pub position: usize,
+
+    /// The index of the empty node at its position, starting at 1.
+    pub index: usize,
+
+    /// The fields of the empty node.
+    pub token: Token,
+}
+
 impl From<&Token> for proto::Token {
     fn from(token: &Token) -> Self {
+        let offsets = token
+            .misc()
+            .get(TOKEN_RANGE)
+            .and_then(Option::as_deref)
+            .and_then(parse_token_range);
+        let (start, end) = offsets.unwrap_or_default();
+        let no_space_after = token.misc().get(SPACE_AFTER).and_then(Option::as_deref) == Some("No");
+
+        // Keep the DEPS column as-is when it cannot be parsed.
+        let (enhanced_dependencies, raw_enhanced_dependencies) = match token.deps() {
+            Some(deps) => match parse_enhanced_dependencies(deps) {
+                Some(enhanced_dependencies) => (enhanced_dependencies, String::new()),
+                None => (Vec::new(), deps.to_string()),
+            },
+            None => (Vec::new(), String::new()),
+        };
+
         proto::Token {
             form: token.form().to_string(),
             lemma: token
@@ -44,8 +130,22 @@ impl From<&Token> for proto::Token {
             misc: token
                 .misc()
                 .iter()
+                .filter(|(attr, _)| match attr.as_str() {
+                    TOKEN_RANGE => offsets.is_none(),
+                    SPACE_AFTER => !no_space_after,
+                    _ => true,
+                })
                 .map(|(attr, val)| (attr.clone(), val.to_owned().unwrap_or_else(String::new)))
                 .collect(),
+            start,
+            end,
+            no_space_after,
+            enhanced_dependencies,
+            raw_enhanced_dependencies,
+            confidences: HashMap::new(),
+            top_k: Vec::new(),
+            allowed_labels: Vec::new(),
+            truncated: false,
         }
     }
 }
@@ -86,20 +186,227 @@ impl From<proto::Token> for Token {
             );
         }
 
+        if proto_token.end != 0 {
+            token.misc_mut().insert(
+                TOKEN_RANGE.to_string(),
+                Some(format!("{}:{}", proto_token.start, proto_token.end)),
+            );
+        }
+
+        if !proto_token.enhanced_dependencies.is_empty() {
+            token.set_deps(Some(
+                proto_token
+                    .enhanced_dependencies
+                    .iter()
+                    .map(|dep| {
+                        if dep.head_empty_node == 0 {
+                            format!("{}:{}", dep.head, dep.relation)
+                        } else {
+                            format!("{}.{}:{}", dep.head, dep.head_empty_node, dep.relation)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("|"),
+            ));
+        }
+
+        if proto_token.enhanced_dependencies.is_empty()
+            && !proto_token.raw_enhanced_dependencies.is_empty()
+        {
+            token.set_deps(Some(proto_token.raw_enhanced_dependencies));
+        }
+
+        if proto_token.no_space_after {
+            token
+                .misc_mut()
+                .insert(SPACE_AFTER.to_string(), Some("No".to_string()));
+        }
+
         token
     }
 }
 
-impl From<proto::Sentence> for Sentence {
-    fn from(sentence: proto::Sentence) -> Self {
+impl From<&AnnotatorError> for proto::SentenceStatus {
+    fn from(err: &AnnotatorError) -> Self {
+        proto::SentenceStatus {
+            error_code: ErrorCode::from(err).code(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Add the scores of a sentence and its tokens to a protobuf sentence.
+pub(crate) fn add_scores(sentence: &mut proto::Sentence, scores: SentenceScores) {
+    sentence.head_scores = scores
+        .head_scores
+        .map(|head_scores| proto::HeadScoreMatrix {
+            n_tokens: head_scores.nrows() as u32,
+            scores: head_scores.iter().copied().collect(),
+        });
+
+    let n_annotated = sentence.tokens.len() - scores.n_truncated_tokens;
+    for token in &mut sentence.tokens[n_annotated..] {
+        token.truncated = true;
+    }
+
+    for (token, token_scores) in sentence.tokens.iter_mut().zip(scores.tokens) {
+        token.confidences = token_scores.confidences;
+        token.top_k = token_scores
+            .top_k
+            .into_iter()
+            .map(|(layer, labels)| proto::TopKLabels {
+                layer,
+                labels: labels
+                    .into_iter()
+                    .map(|label| proto::LabelScore {
+                        label: label.label,
+                        probability: label.probability,
+                    })
+                    .collect(),
+            })
+            .collect();
+    }
+}
+
+/// Check that the heads of a sentence are in the sentence.
+///
+/// Only the heads of tokens with a dependency relation are used, they
+/// must be the root (0) or one of the tokens of the sentence.
+pub(crate) fn check_heads(sentence: &proto::Sentence) -> Result<(), AnnotatorError> {
+    let n_tokens = sentence.tokens.len() as i32;
+    for (idx, token) in sentence.tokens.iter().enumerate() {
+        if !token.relation.is_empty() && (token.head < 0 || token.head > n_tokens) {
+            return Err(AnnotatorError::InvalidConstraint(format!(
+                "the head of token {} is {}, which is not in the sentence",
+                idx + 1,
+                token.head
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// Take the decoding constraints of the tokens of a sentence.
+fn sentence_constraints(sentence: &mut proto::Sentence) -> SentenceConstraints {
+    if sentence
+        .tokens
+        .iter()
+        .all(|token| token.allowed_labels.is_empty())
+    {
+        return SentenceConstraints::default();
+    }
+
+    SentenceConstraints {
+        allowed_labels: sentence
+            .tokens
+            .iter_mut()
+            .map(|token| {
+                mem::take(&mut token.allowed_labels)
+                    .into_iter()
+                    .map(|allowed| (allowed.layer, allowed.labels.into_iter().collect()))
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+/// Parse the enhanced dependencies of the DEPS column.
+///
+/// Returns `None` if any of the dependencies is malformed.
+fn parse_enhanced_dependencies(deps: &str) -> Option<Vec<proto::EnhancedDependency>> {
+    deps.split('|')
+        .map(|dep| {
+            let idx = dep.find(':')?;
+            let head = &dep[..idx];
+            let (head, head_empty_node) = match head.find('.') {
+                Some(dot_idx) => (&head[..dot_idx], head[dot_idx + 1..].parse().ok()?),
+                None => (head, 0),
+            };
+
+            // Empty nodes are numbered from 1, 5.0 is not a valid head.
+            if head.len() != idx && head_empty_node == 0 {
+                return None;
+            }
+
+            Some(proto::EnhancedDependency {
+                head: head.parse().ok()?,
+                head_empty_node,
+                relation: dep[idx + 1..].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Parse a `TokenRange` MISC value.
+fn parse_token_range(range: &str) -> Option<(u32, u32)> {
+    let idx = range.find(':')?;
+    let start = range[..idx].parse().ok()?;
+    let end = range[idx + 1..].parse().ok()?;
+
+    if start < end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+impl From<proto::Comment> for Comment {
+    fn from(comment: proto::Comment) -> Self {
+        if comment.key.is_empty() {
+            Comment::String(comment.value)
+        } else {
+            Comment::AttrVal {
+                attr: comment.key,
+                val: comment.value,
+            }
+        }
+    }
+}
+
+impl From<&Comment> for proto::Comment {
+    fn from(comment: &Comment) -> Self {
+        match comment {
+            Comment::AttrVal { attr, val } => proto::Comment {
+                key: attr.clone(),
+                value: val.clone(),
+            },
+            Comment::String(comment) => proto::Comment {
+                key: String::new(),
+                value: comment.clone(),
+            },
+        }
+    }
+}
+
+impl TryFrom<proto::Sentence> for Sentence {
+    type Error = AnnotatorError;
+
+    fn try_from(sentence: proto::Sentence) -> Result<Self, Self::Error> {
+        check_heads(&sentence)?;
+
         let dep_rels: Vec<_> = sentence
             .tokens
             .iter()
             .map(|t| (t.head, t.relation.clone()))
             .collect();
 
+        // Convert the identifier and metadata to comments.
+        let mut comments: Vec<_> = sentence.metadata.into_iter().map(Comment::from).collect();
+        if !sentence.id.is_empty() {
+            let id_position = cmp::min(sentence.id_position as usize, comments.len());
+            comments.insert(
+                id_position,
+                Comment::AttrVal {
+                    attr: SENT_ID.to_string(),
+                    val: sentence.id,
+                },
+            );
+        }
+
         // Convert tokens.
         let mut sentence: Sentence = sentence.tokens.into_iter().map(Into::into).collect();
+        sentence.set_comments(comments);
 
         // Add dependency relations.
         for (idx, (head, rel)) in dep_rels.into_iter().enumerate() {
@@ -114,7 +421,7 @@ impl From<proto::Sentence> for Sentence {
             }
         }
 
-        sentence
+        Ok(sentence)
     }
 }
 
@@ -137,19 +444,136 @@ impl From<Sentence> for proto::Sentence {
             }
         }
 
-        proto::Sentence { tokens }
+        let mut id = String::new();
+        let mut id_position = 0;
+        let mut metadata = Vec::new();
+        for comment in sentence.comments() {
+            match comment {
+                Comment::AttrVal { attr, val } if attr == SENT_ID && id.is_empty() => {
+                    id = val.clone();
+                    id_position = metadata.len() as u32;
+                }
+                comment => metadata.push(proto::Comment::from(comment)),
+            }
+        }
+
+        proto::Sentence {
+            tokens,
+            id,
+            id_position,
+            metadata,
+            multiword_tokens: Vec::new(),
+            empty_nodes: Vec::new(),
+            head_scores: None,
+            status: None,
+        }
+    }
+}
+
+impl TryFrom<proto::Sentence> for ExtendedSentence {
+    type Error = AnnotatorError;
+
+    fn try_from(mut sentence: proto::Sentence) -> Result<Self, Self::Error> {
+        let multiword_tokens = mem::take(&mut sentence.multiword_tokens)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let empty_nodes = mem::take(&mut sentence.empty_nodes)
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        let constraints = sentence_constraints(&mut sentence);
+
+        Ok(ExtendedSentence {
+            sentence: sentence.try_into()?,
+            multiword_tokens,
+            empty_nodes,
+            constraints,
+        })
     }
 }
 
-impl From<proto::Sentences> for Sentences {
-    fn from(sentences: proto::Sentences) -> Self {
-        Sentences(
+impl From<ExtendedSentence> for proto::Sentence {
+    fn from(sentence: ExtendedSentence) -> Self {
+        let mut proto_sentence = proto::Sentence::from(sentence.sentence);
+        proto_sentence.multiword_tokens = sentence
+            .multiword_tokens
+            .into_iter()
+            .map(Into::into)
+            .collect();
+        proto_sentence.empty_nodes = sentence.empty_nodes.into_iter().map(Into::into).collect();
+        proto_sentence
+    }
+}
+
+impl From<proto::MultiwordToken> for MultiwordToken {
+    fn from(token: proto::MultiwordToken) -> Self {
+        MultiwordToken {
+            first: token.first as usize,
+            last: token.last as usize,
+            form: token.form,
+            misc: token
+                .misc
+                .into_iter()
+                .map(|(k, v)| {
+                    if v.is_empty() {
+                        (k, None)
+                    } else {
+                        (k, Some(v))
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<MultiwordToken> for proto::MultiwordToken {
+    fn from(token: MultiwordToken) -> Self {
+        proto::MultiwordToken {
+            first: token.first as u32,
+            last: token.last as u32,
+            form: token.form,
+            misc: token
+                .misc
+                .into_inner()
+                .into_iter()
+                .map(|(k, v)| (k, v.unwrap_or_default()))
+                .collect(),
+        }
+    }
+}
+
+impl From<proto::EmptyNode> for EmptyNode {
+    fn from(node: proto::EmptyNode) -> Self {
+        EmptyNode {
+            position: node.position as usize,
+            index: node.index as usize,
+            token: node.token.unwrap_or_default().into(),
+        }
+    }
+}
+
+impl From<EmptyNode> for proto::EmptyNode {
+    fn from(node: EmptyNode) -> Self {
+        proto::EmptyNode {
+            position: node.position as u32,
+            index: node.index as u32,
+            token: Some((&node.token).into()),
+        }
+    }
+}
+
+impl TryFrom<proto::Sentences> for Sentences {
+    type Error = AnnotatorError;
+
+    fn try_from(sentences: proto::Sentences) -> Result<Self, Self::Error> {
+        Ok(Sentences(
             sentences
                 .sentences
                 .into_iter()
-                .map(|sentence| sentence.into())
-                .collect(),
-        )
+                .map(TryInto::try_into)
+                .collect::<Result<_, _>>()?,
+        ))
     }
 }
 
@@ -163,3 +587,348 @@ impl From<Sentences> for proto::Sentences {
 
 implement_into_ffi_by_protobuf!(proto::Sentences);
 implement_into_ffi_by_delegation!(Sentences, proto::Sentences);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::convert::TryFrom;
+    use std::iter::FromIterator;
+
+    use ffi_support::ErrorCode;
+    use ndarray::array;
+    use udgraph::graph::{Comment, DepTriple, Sentence};
+    use udgraph::token::{Features, Token, TokenBuilder};
+
+    use super::{add_scores, proto, ExtendedSentence, Sentences};
+    use crate::error::error_codes::{INVALID_CONSTRAINT_ERROR, SEQUENCE_TOO_LONG_ERROR};
+    use crate::tagger::{LabelScore, SentenceScores, TokenScores};
+    use crate::AnnotatorError;
+
+    #[test]
+    fn sentence_metadata_round_trips() {
+        let proto_sentence = proto::Sentence {
+            tokens: vec![proto::Token {
+                form: "Test".to_string(),
+                ..Default::default()
+            }],
+            id: "s1".to_string(),
+            metadata: vec![
+                proto::Comment {
+                    key: "text".to_string(),
+                    value: "Test".to_string(),
+                },
+                proto::Comment {
+                    key: String::new(),
+                    value: "newdoc".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let sentence = Sentence::try_from(proto_sentence.clone()).unwrap();
+        assert_eq!(
+            sentence.comments(),
+            &[
+                Comment::AttrVal {
+                    attr: "sent_id".to_string(),
+                    val: "s1".to_string()
+                },
+                Comment::AttrVal {
+                    attr: "text".to_string(),
+                    val: "Test".to_string()
+                },
+                Comment::String("newdoc".to_string()),
+            ]
+        );
+
+        assert_eq!(proto::Sentence::from(sentence), proto_sentence);
+
+        // The identifier is written last when its position exceeds the
+        // number of metadata comments.
+        let sentence = Sentence::try_from(proto::Sentence {
+            id_position: 5,
+            ..proto_sentence
+        })
+        .unwrap();
+        assert_eq!(
+            sentence.comments().last(),
+            Some(&Comment::AttrVal {
+                attr: "sent_id".to_string(),
+                val: "s1".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn heads_must_be_in_the_sentence() {
+        for &head in &[-1, 3] {
+            let proto_sentence = proto::Sentence {
+                tokens: vec![
+                    proto::Token {
+                        form: "Dit".to_string(),
+                        head,
+                        relation: "nsubj".to_string(),
+                        ..Default::default()
+                    },
+                    proto::Token {
+                        form: "werkt".to_string(),
+                        ..Default::default()
+                    },
+                ],
+                ..Default::default()
+            };
+
+            let err = Sentence::try_from(proto_sentence).unwrap_err();
+            assert_eq!(
+                ErrorCode::from(&err),
+                ErrorCode::new(INVALID_CONSTRAINT_ERROR)
+            );
+        }
+    }
+
+    #[test]
+    fn sentences_can_be_read_from_json() {
+        let json = r#"{"sentences": [{"tokens": [
+            {"form": "Dit", "upos": "PRON", "features": {"PronType": "Dem"}, "head": 2, "relation": "nsubj"},
+            {"form": "werkt", "lemma": "", "head": 0, "relation": "root"}
+        ]}]}"#;
+        let sentences =
+            Sentences::try_from(serde_json::from_str::<proto::Sentences>(json).unwrap()).unwrap();
+
+        let mut check = Sentence::from_iter(vec![
+            TokenBuilder::new("Dit")
+                .upos("PRON")
+                .features(Features::from_iter(vec![(
+                    "PronType".to_string(),
+                    "Dem".to_string(),
+                )]))
+                .into(),
+            Token::new("werkt"),
+        ]);
+        check
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(2, Some("nsubj"), 1));
+        check
+            .dep_graph_mut()
+            .add_deprel(DepTriple::new(0, Some("root"), 2));
+
+        assert_eq!(sentences.0, vec![check]);
+    }
+
+    #[test]
+    fn allowed_labels_are_converted_to_constraints() {
+        let proto_sentence = proto::Sentence {
+            tokens: vec![
+                proto::Token {
+                    form: "Dit".to_string(),
+                    ..Default::default()
+                },
+                proto::Token {
+                    form: "werkt".to_string(),
+                    allowed_labels: vec![proto::AllowedLabels {
+                        layer: "upos".to_string(),
+                        labels: vec!["VERB".to_string(), "AUX".to_string()],
+                    }],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let sentence = ExtendedSentence::try_from(proto_sentence.clone()).unwrap();
+        assert_eq!(sentence.constraints.allowed_labels(0, "upos"), None);
+        assert_eq!(
+            sentence.constraints.allowed_labels(1, "upos"),
+            Some(&HashSet::from_iter(vec![
+                "VERB".to_string(),
+                "AUX".to_string()
+            ]))
+        );
+
+        // Constraints are not returned.
+        let mut check = proto_sentence;
+        check.tokens[1].allowed_labels.clear();
+        assert_eq!(proto::Sentence::from(sentence), check);
+    }
+
+    #[test]
+    fn enhanced_dependencies_round_trip() {
+        let token: Token = TokenBuilder::new("hem").deps("2:obj|4.1:obl:arg").into();
+
+        let proto_token = proto::Token::from(&token);
+        assert_eq!(
+            proto_token.enhanced_dependencies,
+            vec![
+                proto::EnhancedDependency {
+                    head: 2,
+                    head_empty_node: 0,
+                    relation: "obj".to_string(),
+                },
+                proto::EnhancedDependency {
+                    head: 4,
+                    head_empty_node: 1,
+                    relation: "obl:arg".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(Token::from(proto_token), token);
+    }
+
+    #[test]
+    fn malformed_enhanced_dependencies_round_trip() {
+        for deps in &["2:obj|4", "x:obj", "4.x:obl", "4.0:obl", "2:obj|:nsubj"] {
+            let token: Token = TokenBuilder::new("hem").deps(*deps).into();
+
+            let proto_token = proto::Token::from(&token);
+            assert!(proto_token.enhanced_dependencies.is_empty());
+            assert_eq!(proto_token.raw_enhanced_dependencies, *deps);
+
+            assert_eq!(Token::from(proto_token), token);
+        }
+    }
+
+    #[test]
+    fn token_offsets_round_trip() {
+        let proto_token = proto::Token {
+            form: "test".to_string(),
+            misc: vec![("Translit".to_string(), "test".to_string())]
+                .into_iter()
+                .collect(),
+            start: 4,
+            end: 8,
+            no_space_after: true,
+            ..Default::default()
+        };
+
+        let token = Token::from(proto_token.clone());
+        assert_eq!(
+            token.misc().get("TokenRange"),
+            Some(&Some("4:8".to_string()))
+        );
+        assert_eq!(
+            token.misc().get("SpaceAfter"),
+            Some(&Some("No".to_string()))
+        );
+
+        assert_eq!(proto::Token::from(&token), proto_token);
+    }
+
+    #[test]
+    fn token_scores_are_added_to_tokens() {
+        let mut proto_sentence = proto::Sentence::from(Sentence::from_iter(vec![
+            Token::new("Dit"),
+            Token::new("werkt"),
+        ]));
+        let scores = vec![
+            TokenScores {
+                confidences: vec![("upos".to_string(), 0.9), ("head".to_string(), 0.5)]
+                    .into_iter()
+                    .collect(),
+                top_k: vec![(
+                    "upos".to_string(),
+                    vec![
+                        LabelScore {
+                            label: "PRON".to_string(),
+                            probability: 0.9,
+                        },
+                        LabelScore {
+                            label: "DET".to_string(),
+                            probability: 0.05,
+                        },
+                    ],
+                )]
+                .into_iter()
+                .collect(),
+            },
+            TokenScores::default(),
+        ];
+
+        add_scores(
+            &mut proto_sentence,
+            SentenceScores {
+                tokens: scores.clone(),
+                head_scores: None,
+                n_truncated_tokens: 0,
+            },
+        );
+        assert_eq!(proto_sentence.tokens[0].confidences, scores[0].confidences);
+        assert_eq!(
+            proto_sentence.tokens[0].top_k,
+            vec![proto::TopKLabels {
+                layer: "upos".to_string(),
+                labels: vec![
+                    proto::LabelScore {
+                        label: "PRON".to_string(),
+                        probability: 0.9,
+                    },
+                    proto::LabelScore {
+                        label: "DET".to_string(),
+                        probability: 0.05,
+                    },
+                ],
+            }]
+        );
+        assert!(proto_sentence.tokens[1].confidences.is_empty());
+        assert!(proto_sentence.tokens[1].top_k.is_empty());
+        assert_eq!(proto_sentence.head_scores, None);
+    }
+
+    #[test]
+    fn head_scores_are_added_to_sentences() {
+        let mut proto_sentence = proto::Sentence::from(Sentence::from_iter(vec![
+            Token::new("Dit"),
+            Token::new("werkt"),
+        ]));
+
+        add_scores(
+            &mut proto_sentence,
+            SentenceScores {
+                tokens: Vec::new(),
+                head_scores: Some(array![[0.1, 0.2, 0.7], [0.8, 0.15, 0.05]]),
+                n_truncated_tokens: 0,
+            },
+        );
+        assert_eq!(
+            proto_sentence.head_scores,
+            Some(proto::HeadScoreMatrix {
+                n_tokens: 2,
+                scores: vec![0.1, 0.2, 0.7, 0.8, 0.15, 0.05],
+            })
+        );
+        assert!(proto_sentence.tokens[0].confidences.is_empty());
+    }
+
+    #[test]
+    fn truncated_tokens_are_marked() {
+        let mut proto_sentence = proto::Sentence::from(Sentence::from_iter(vec![
+            Token::new("Dit"),
+            Token::new("werkt"),
+            Token::new("niet"),
+        ]));
+
+        add_scores(
+            &mut proto_sentence,
+            SentenceScores {
+                n_truncated_tokens: 2,
+                ..Default::default()
+            },
+        );
+        let truncated = proto_sentence
+            .tokens
+            .iter()
+            .map(|token| token.truncated)
+            .collect::<Vec<_>>();
+        assert_eq!(truncated, vec![false, true, true]);
+    }
+
+    #[test]
+    fn sentence_errors_are_converted_to_status() {
+        let status = proto::SentenceStatus::from(&AnnotatorError::SequenceTooLong(600, 512));
+        assert_eq!(status.error_code, SEQUENCE_TOO_LONG_ERROR);
+        assert_eq!(
+            status.message,
+            "Sentence has 600 pieces, the maximum sequence length is 512"
+        );
+    }
+}
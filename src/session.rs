@@ -0,0 +1,231 @@
+use std::cmp;
+use std::iter;
+use std::mem;
+
+use udgraph::graph::Sentence;
+use udgraph::token::Token;
+
+use crate::annotator::Annotator;
+use crate::options::AnnotateOptions;
+use crate::sentences::{check_heads, proto};
+use crate::AnnotatorError;
+
+/// The maximum number of sentences that a session buffers.
+///
+/// Both pending sentences and annotated sentences that were not polled
+/// yet count towards this limit. The limit is raised to the batch size
+/// when the batch size is larger.
+pub const MAX_BUFFERED_SENTENCES: usize = 4096;
+
+/// A streaming annotation session.
+///
+/// Sentences that are pushed to a session are annotated as soon as a
+/// full batch is pending, so that batches can span several pushes. The
+/// annotated sentences are kept until they are polled. Errors are always
+/// reported per sentence, so that no sentence is lost when another
+/// sentence of its batch cannot be annotated.
+pub struct Session {
+    annotator: u64,
+    options: AnnotateOptions,
+    pending: Vec<proto::Sentence>,
+    pending_pieces: Vec<usize>,
+    annotated: Vec<proto::Sentence>,
+}
+
+impl Session {
+    /// Construct a session for the annotator with the given handle.
+    pub fn new(annotator: u64, mut options: AnnotateOptions) -> Self {
+        options.per_sentence_errors = true;
+
+        Session {
+            annotator,
+            options,
+            pending: Vec::new(),
+            pending_pieces: Vec::new(),
+            annotated: Vec::new(),
+        }
+    }
+
+    /// The handle of the annotator of this session.
+    pub fn annotator(&self) -> u64 {
+        self.annotator
+    }
+
+    /// Add sentences to the session.
+    ///
+    /// Pending sentences are annotated in full batches. When the
+    /// sentences would exceed the number of sentences that the session
+    /// buffers, none of them are added and `SessionFull` is returned.
+    /// Likewise, none of the sentences are added when the head of a
+    /// token is not in its sentence.
+    /// Sentences are only removed from the pending sentences once they
+    /// are annotated, so they are annotated by a later push or poll when
+    /// annotation fails.
+    pub fn push(
+        &mut self,
+        annotator: &Annotator,
+        sentences: Vec<proto::Sentence>,
+    ) -> Result<(), AnnotatorError> {
+        let max_buffered = self.max_buffered(annotator);
+        if self.pending.len() + self.annotated.len() + sentences.len() > max_buffered {
+            return Err(AnnotatorError::SessionFull(max_buffered));
+        }
+
+        for sentence in &sentences {
+            check_heads(sentence)?;
+        }
+
+        // Pieces are only counted when they limit the batch size.
+        if self.options.max_batch_pieces.is_some() {
+            self.pending_pieces.extend(
+                sentences
+                    .iter()
+                    .map(|sentence| n_pieces(annotator, sentence)),
+            );
+        } else {
+            self.pending_pieces
+                .resize(self.pending_pieces.len() + sentences.len(), 0);
+        }
+        self.pending.extend(sentences);
+
+        let batch_size = annotator.batch_size(self.options.batch_size);
+        while let Some(n_sentences) = self.full_batch_len(batch_size) {
+            self.annotate(annotator, n_sentences)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the sentences that were annotated so far.
+    ///
+    /// If `flush` is true, the pending sentences are annotated first,
+    /// even if they do not fill a batch.
+    pub fn poll(
+        &mut self,
+        annotator: &Annotator,
+        flush: bool,
+    ) -> Result<proto::Sentences, AnnotatorError> {
+        if flush {
+            let batch_size = annotator.batch_size(self.options.batch_size);
+            while !self.pending.is_empty() {
+                let n_sentences = self
+                    .full_batch_len(batch_size)
+                    .unwrap_or_else(|| self.pending.len());
+                self.annotate(annotator, n_sentences)?;
+            }
+        }
+
+        Ok(proto::Sentences {
+            sentences: mem::take(&mut self.annotated),
+        })
+    }
+
+    /// Annotate the first `n_sentences` pending sentences.
+    ///
+    /// The sentences are removed from the pending sentences after they
+    /// were annotated successfully.
+    fn annotate(
+        &mut self,
+        annotator: &Annotator,
+        n_sentences: usize,
+    ) -> Result<(), AnnotatorError> {
+        let sentences = proto::Sentences {
+            sentences: self.pending[..n_sentences].to_vec(),
+        };
+        let annotated = crate::annotate_proto_sentences(annotator, sentences, &self.options)?;
+
+        self.pending.drain(..n_sentences);
+        self.pending_pieces.drain(..n_sentences);
+        self.annotated.extend(annotated.sentences);
+
+        Ok(())
+    }
+
+    /// Get the number of pending sentences that form a full batch.
+    ///
+    /// A batch is full when it contains `batch_size` sentences or when
+    /// the next sentence would exceed the maximum number of pieces in a
+    /// batch. Returns `None` if the pending sentences do not fill a batch.
+    fn full_batch_len(&self, batch_size: usize) -> Option<usize> {
+        full_batch_len(
+            &self.pending_pieces,
+            batch_size,
+            self.options.max_batch_pieces,
+        )
+    }
+
+    /// The maximum number of sentences that the session buffers.
+    fn max_buffered(&self, annotator: &Annotator) -> usize {
+        cmp::max(
+            MAX_BUFFERED_SENTENCES,
+            annotator.batch_size(self.options.batch_size),
+        )
+    }
+}
+
+/// Get the number of sentences that form a full batch.
+///
+/// `sentence_pieces` are the number of pieces of the sentences, in
+/// the order in which they are batched. The size of a batch is the
+/// number of sentences times the number of pieces of its longest
+/// sentence. A batch always contains at least one sentence.
+fn full_batch_len(
+    sentence_pieces: &[usize],
+    batch_size: usize,
+    max_batch_pieces: Option<usize>,
+) -> Option<usize> {
+    let mut max_pieces = 0;
+    for (idx, &n_pieces) in sentence_pieces.iter().enumerate() {
+        max_pieces = cmp::max(max_pieces, n_pieces);
+
+        let n_sentences = idx + 1;
+        let exceeds_max_pieces = max_batch_pieces
+            .map(|max_batch_pieces| n_sentences * max_pieces > max_batch_pieces)
+            .unwrap_or(false);
+        if n_sentences > 1 && exceeds_max_pieces {
+            return Some(idx);
+        }
+
+        if n_sentences == batch_size {
+            return Some(n_sentences);
+        }
+    }
+
+    None
+}
+
+/// Get the number of pieces of a sentence.
+fn n_pieces(annotator: &Annotator, sentence: &proto::Sentence) -> usize {
+    let mut forms = Sentence::new();
+    for token in &sentence.tokens {
+        forms.push(Token::new(token.form.as_str()));
+    }
+
+    annotator
+        .tokenize_sentences(iter::once(forms))
+        .pop()
+        .map(|sentence| sentence.pieces.len())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::full_batch_len;
+
+    #[test]
+    fn batch_is_full_at_batch_size() {
+        assert_eq!(full_batch_len(&[0, 0], 3, None), None);
+        assert_eq!(full_batch_len(&[0, 0, 0], 3, None), Some(3));
+        assert_eq!(full_batch_len(&[0, 0, 0, 0], 3, None), Some(3));
+    }
+
+    #[test]
+    fn batch_is_full_at_max_batch_pieces() {
+        // The third sentence would make the batch 3 * 6 pieces.
+        assert_eq!(full_batch_len(&[4, 5, 6], 8, Some(16)), Some(2));
+        assert_eq!(full_batch_len(&[4, 5], 8, Some(16)), None);
+
+        // A batch always contains at least one sentence.
+        assert_eq!(full_batch_len(&[20, 1], 8, Some(16)), Some(1));
+    }
+}
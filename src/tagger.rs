@@ -0,0 +1,992 @@
+//! Sequence labeling and dependency parsing.
+//!
+//! This tagger is a variant of the syntaxdot tagger that can also
+//! return the probabilities of the labels that it assigns.
+
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::iter;
+
+use ndarray::{
+    s, Array, Array1, Array2, Array3, Array4, ArrayD, ArrayView2, Axis, Dimension, ShapeError,
+};
+use syntaxdot::encoders::{EncoderType, Encoders, NamedEncoder};
+use syntaxdot::error::SyntaxDotError;
+use syntaxdot::model::bert::{BertModel, FreezeLayers};
+use syntaxdot::model::biaffine_dependency_layer::BiaffineScoreLogits;
+use syntaxdot::tensor::{TensorBuilder, Tensors};
+use syntaxdot_encoders::dependency::ImmutableDependencyEncoder;
+use syntaxdot_encoders::layer::LayerValue;
+use syntaxdot_encoders::{EncodingProb, SentenceDecoder};
+use syntaxdot_tokenizers::SentenceWithPieces;
+use syntaxdot_transformers::models::LayerOutput;
+use tch::{Device, Kind, Tensor};
+use udgraph::graph::Sentence;
+use udgraph::token::Tokens;
+
+use crate::constraints::{
+    dependency_policy, fix_present_arcs, keep_present_dependencies, keep_present_values,
+    SentenceConstraints,
+};
+use crate::labels::{EncoderLabels, N_RESERVED_LABELS};
+use crate::options::{AnnotateOptions, AnnotationPolicy, HeadScores};
+use crate::windows::{sliding_windows, window_pieces, SlidingWindow, TokenWindow};
+
+/// Score name of the head that is assigned by the biaffine parser.
+pub const HEAD: &str = "head";
+
+/// Score name of the relation that is assigned by the biaffine parser.
+pub const RELATION: &str = "relation";
+
+/// The number of labels that a sequence label decoder can choose from.
+const TOP_K: usize = 3;
+
+/// Scores of a sentence.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SentenceScores {
+    /// Label scores of the tokens, empty when no label scores were requested.
+    pub tokens: Vec<TokenScores>,
+
+    /// Head scores of the biaffine parser with shape `[n_tokens, n_tokens + 1]`.
+    ///
+    /// Row `i` contains the scores of the heads of token `i + 1`, column `j`
+    /// the scores of head `j`, where `0` is the root.
+    pub head_scores: Option<Array2<f32>>,
+
+    /// The number of tokens at the end of the sentence that were not
+    /// annotated, because the sentence was truncated.
+    pub n_truncated_tokens: usize,
+}
+
+/// Label scores of a token.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenScores {
+    /// Probability of the chosen label of each encoder.
+    ///
+    /// Sequence labels use the encoder name, the head and relation of
+    /// the biaffine parser use [`HEAD`] and [`RELATION`].
+    pub confidences: HashMap<String, f32>,
+
+    /// The most probable labels of each layer, from most to least probable.
+    pub top_k: BTreeMap<String, Vec<LabelScore>>,
+}
+
+/// A label with its probability.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LabelScore {
+    pub label: String,
+    pub probability: f32,
+}
+
+/// A biaffine dependency decoder with its relation inventory.
+pub struct BiaffineDecoder {
+    /// The decoder.
+    pub decoder: ImmutableDependencyEncoder,
+
+    /// The names of the relations, indexed by their encodings.
+    pub relations: Vec<String>,
+}
+
+/// The decoders of a tagger.
+pub struct Decoders {
+    /// The biaffine decoder, if the biaffine parser is used.
+    pub biaffine: Option<BiaffineDecoder>,
+
+    /// The sequence labeling encoders.
+    pub encoders: Encoders,
+
+    /// The label inventories of the sequence labeling encoders, by
+    /// encoder name.
+    pub labels: HashMap<String, EncoderLabels>,
+}
+
+/// A sequence tagger.
+pub struct Tagger {
+    biaffine_decoder: Option<BiaffineDecoder>,
+    device: Device,
+    encoder_labels: HashMap<String, EncoderLabels>,
+    encoders: Encoders,
+    encoder_types: HashMap<String, EncoderType>,
+    model: BertModel,
+    sequence_labels: HashMap<String, HashMap<String, usize>>,
+}
+
+impl Tagger {
+    /// Construct a new tagger.
+    ///
+    /// `encoder_types` maps encoder names to their configured types.
+    pub fn new(
+        device: Device,
+        model: BertModel,
+        decoders: Decoders,
+        encoder_types: HashMap<String, EncoderType>,
+    ) -> Self {
+        let sequence_labels = decoders
+            .labels
+            .iter()
+            .filter_map(|(name, labels)| Some((name.clone(), labels.layer_labels()?)))
+            .collect();
+
+        Tagger {
+            biaffine_decoder: decoders.biaffine,
+            device,
+            encoder_labels: decoders.labels,
+            encoders: decoders.encoders,
+            encoder_types,
+            model,
+            sequence_labels,
+        }
+    }
+
+    /// Check whether the tagger assigns the layer with the given name.
+    pub fn has_layer(&self, name: &str) -> bool {
+        self.encoders.iter().any(|encoder| encoder.name() == name)
+            || (self.biaffine_decoder.is_some() && (name == HEAD || name == RELATION))
+    }
+
+    /// Get the labels of a sequence labeling layer with their encodings.
+    ///
+    /// Returns `None` if the layer is not a sequence labeling layer.
+    pub fn sequence_labels(&self, layer: &str) -> Option<&HashMap<String, usize>> {
+        self.sequence_labels.get(layer)
+    }
+
+    /// Tag sentences.
+    ///
+    /// Every sentence is decoded using its constraints. Sentences that
+    /// are longer than the maximum length of `window` are encoded in
+    /// sliding windows. Returns the scores of every sentence. Scores that
+    /// were not requested in `options` are empty.
+    pub fn tag_sentences(
+        &self,
+        sentences: &mut [&mut SentenceWithPieces],
+        constraints: &[&SentenceConstraints],
+        window: Option<SlidingWindow>,
+        options: &AnnotateOptions,
+    ) -> Result<Vec<SentenceScores>, SyntaxDotError> {
+        let tensors = self.prepare_batch(sentences);
+
+        // Get model predictions.
+        let attention_mask = tensors.seq_lens.attention_mask()?;
+        let token_spans = tensors.token_spans.to_device(self.device);
+        let (biaffine_score_logits, encoder_logits) = tch::no_grad(|| {
+            let encoding = match window {
+                Some(window)
+                    if sentences
+                        .iter()
+                        .any(|sentence| sentence.pieces.len() > window.max_len) =>
+                {
+                    self.encode_windows(sentences, window)?
+                }
+                _ => self.model.encode(
+                    &tensors.inputs.to_device(self.device),
+                    &attention_mask.to_device(self.device),
+                    &token_spans,
+                    false,
+                    FreezeLayers {
+                        embeddings: true,
+                        encoder: true,
+                        classifiers: true,
+                    },
+                )?,
+            };
+
+            // The biaffine parser is expensive, only run it when its
+            // output is used.
+            let biaffine_score_logits = if dependency_policy(options) != AnnotationPolicy::Skip
+                || options.head_scores != HeadScores::None
+            {
+                self.model.biaffine_logits_from_encoding(
+                    &encoding,
+                    &token_spans.token_mask()?,
+                    false,
+                )?
+            } else {
+                None
+            };
+            let encoder_logits = self.model.encoder_logits_from_encoding(&encoding, false)?;
+
+            Ok::<_, SyntaxDotError>((biaffine_score_logits, encoder_logits))
+        })?;
+
+        let mut scores = sentences
+            .iter()
+            .map(|sentence| SentenceScores {
+                tokens: if options.label_scores_requested() {
+                    vec![TokenScores::default(); sentence.token_offsets.len()]
+                } else {
+                    Vec::new()
+                },
+                head_scores: None,
+                n_truncated_tokens: 0,
+            })
+            .collect::<Vec<_>>();
+
+        // Decode dependencies before sequence labels. Biaffine parsing does not require any
+        // other annotations. Sequence labelers, however, may require dependencies (e.g. the
+        // TüBa-D/Z lemmatizer).
+        if let (Some(decoder), Some(biaffine_score_logits)) =
+            (self.biaffine_decoder.as_ref(), biaffine_score_logits)
+        {
+            tch::no_grad(|| {
+                self.decode_biaffine(
+                    decoder,
+                    sentences,
+                    biaffine_score_logits,
+                    &mut scores,
+                    options,
+                )
+            })?
+        }
+
+        tch::no_grad(|| {
+            self.decode_sequence_labels(
+                sentences,
+                constraints,
+                encoder_logits,
+                &mut scores,
+                options,
+            )
+        })?;
+
+        Ok(scores)
+    }
+
+    /// Compute token embeddings.
+    ///
+    /// The embeddings are a weighted sum of the outputs of the given
+    /// layers, where word pieces are pooled using the pooler of the
+    /// model. Returns a matrix with shape `[n_tokens + 1, dims]` for every
+    /// sentence. The first row is the embedding of the root, which is the
+    /// first piece of the sentence.
+    pub fn embed_sentences(
+        &self,
+        sentences: &[&mut SentenceWithPieces],
+        layer_weights: &[(usize, f32)],
+    ) -> Result<Vec<Array2<f32>>, SyntaxDotError> {
+        let tensors = self.prepare_batch(sentences);
+
+        let attention_mask = tensors.seq_lens.attention_mask()?;
+        let token_spans = tensors.token_spans.to_device(self.device);
+        let embeddings = tch::no_grad(|| {
+            let layer_outputs = self.model.encode(
+                &tensors.inputs.to_device(self.device),
+                &attention_mask.to_device(self.device),
+                &token_spans,
+                false,
+                FreezeLayers {
+                    embeddings: true,
+                    encoder: true,
+                    classifiers: true,
+                },
+            )?;
+
+            let mut embeddings: Option<Tensor> = None;
+            for &(layer, weight) in layer_weights {
+                let weighted = layer_outputs[layer].output().f_mul1(f64::from(weight))?;
+                embeddings = Some(match embeddings {
+                    Some(embeddings) => embeddings.f_add(&weighted)?,
+                    None => weighted,
+                });
+            }
+
+            Ok::<_, SyntaxDotError>(
+                embeddings.expect("At least one layer is required for embeddings"),
+            )
+        })?;
+
+        let embeddings: Array3<f32> = to_array(&embeddings)?;
+
+        Ok(sentences
+            .iter()
+            .enumerate()
+            .map(|(idx, sentence)| {
+                embeddings
+                    .slice(s![idx, ..sentence.token_offsets.len() + 1, ..])
+                    .to_owned()
+            })
+            .collect())
+    }
+
+    /// Encode sentences in sliding windows.
+    ///
+    /// Each window is encoded separately, with the first piece of the
+    /// sentence as its first piece and the end piece of the sentence, if
+    /// any, as its last piece. The token representations of the
+    /// windows are then merged into representations of the sentences,
+    /// using the window that owns each token. The root is represented
+    /// by the first window.
+    fn encode_windows(
+        &self,
+        sentences: &[&mut SentenceWithPieces],
+        window: SlidingWindow,
+    ) -> Result<Vec<LayerOutput>, SyntaxDotError> {
+        let mut window_sentences = Vec::new();
+
+        // For each sentence, the window and position in the window that
+        // represents the root and every token.
+        let mut sources = Vec::with_capacity(sentences.len());
+
+        for sentence in sentences {
+            let windows = if sentence.pieces.len() > window.max_len {
+                sliding_windows(&sentence.token_offsets, sentence.pieces.len(), window)
+            } else {
+                let n_tokens = sentence.token_offsets.len();
+                vec![TokenWindow {
+                    tokens: 0..n_tokens,
+                    owned: 0..n_tokens,
+                }]
+            };
+
+            let mut sentence_sources = vec![(window_sentences.len(), 0)];
+            for token_window in windows {
+                sentence_sources.extend(token_window.owned.clone().map(|token| {
+                    (
+                        window_sentences.len(),
+                        token - token_window.tokens.start + 1,
+                    )
+                }));
+                window_sentences.push(window_pieces(sentence, &token_window, window));
+            }
+
+            sources.push(sentence_sources);
+        }
+
+        let window_refs = window_sentences.iter_mut().collect::<Vec<_>>();
+        let tensors = self.prepare_batch(&window_refs);
+        let attention_mask = tensors.seq_lens.attention_mask()?;
+        let encoding = self.model.encode(
+            &tensors.inputs.to_device(self.device),
+            &attention_mask.to_device(self.device),
+            &tensors.token_spans.to_device(self.device),
+            false,
+            FreezeLayers {
+                embeddings: true,
+                encoder: true,
+                classifiers: true,
+            },
+        )?;
+
+        let window_len = encoding
+            .first()
+            .map(|layer| layer.output().size()[1])
+            .unwrap_or(0);
+        let max_sentence_len = sources.iter().map(Vec::len).max().unwrap_or(0);
+
+        // Indices of the token representations in the flattened window
+        // representations. Padding uses the first representation, it is
+        // masked like the padding of sentences that are not windowed.
+        let indices = sources
+            .iter()
+            .flat_map(|sentence_sources| {
+                sentence_sources
+                    .iter()
+                    .map(|&(window_idx, token)| window_idx as i64 * window_len + token as i64)
+                    .chain(iter::repeat(0))
+                    .take(max_sentence_len)
+            })
+            .collect::<Vec<_>>();
+        let indices = Tensor::of_slice(&indices).to_device(self.device);
+
+        encoding
+            .iter()
+            .map(|layer| {
+                layer.map_output(|output| {
+                    let hidden_size = output.size()[2];
+                    Ok(output
+                        .f_reshape(&[-1, hidden_size])?
+                        .f_index_select(0, &indices)?
+                        .f_reshape(&[
+                            sentences.len() as i64,
+                            max_sentence_len as i64,
+                            hidden_size,
+                        ])?)
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map_err(SyntaxDotError::BertError)
+    }
+
+    /// Construct the tensor representations of a batch of sentences.
+    fn prepare_batch(&self, sentences: &[&mut SentenceWithPieces]) -> Tensors {
+        let max_seq_len = sentences
+            .iter()
+            .map(|sentence| sentence.pieces.len())
+            .max()
+            .unwrap_or(0);
+
+        let max_tokens_len = sentences
+            .iter()
+            .map(|sentence| sentence.token_offsets.len())
+            .max()
+            .unwrap_or(0);
+
+        let mut builder: TensorBuilder =
+            TensorBuilder::new_without_labels(sentences.len(), max_seq_len, max_tokens_len);
+
+        for sentence in sentences {
+            let input = sentence.pieces.view();
+            let mut token_mask = Array1::zeros((input.len(),));
+            for token_idx in &sentence.token_offsets {
+                token_mask[*token_idx] = 1;
+            }
+
+            let token_offsets = sentence
+                .token_offsets
+                .iter()
+                .map(|&offset| offset as i32)
+                .collect::<Array1<i32>>();
+
+            let token_lens: Array1<i32> =
+                Array1::from_shape_fn((sentence.token_offsets.len(),), |idx| {
+                    if idx + 1 < sentence.token_offsets.len() {
+                        sentence.token_offsets[idx + 1] as i32 - sentence.token_offsets[idx] as i32
+                    } else {
+                        sentence.pieces.len() as i32 - sentence.token_offsets[idx] as i32
+                    }
+                });
+
+            builder.add_without_labels(
+                input.view(),
+                token_offsets.view(),
+                token_lens.view(),
+                token_mask.view(),
+            );
+        }
+
+        builder.into()
+    }
+
+    /// Decode biaffine score matrices.
+    ///
+    /// The score matrices do not have the root as a dependent, their
+    /// shape is `[batch_size, seq_len - 1, seq_len]`.
+    fn decode_biaffine(
+        &self,
+        decoder: &BiaffineDecoder,
+        sentences: &mut [&mut SentenceWithPieces],
+        biaffine_score_logits: BiaffineScoreLogits,
+        scores: &mut [SentenceScores],
+        options: &AnnotateOptions,
+    ) -> Result<(), SyntaxDotError> {
+        let head_score_logits: Array3<f32> = to_array(&biaffine_score_logits.head_score_logits)?;
+
+        let policy = dependency_policy(options);
+        let head_k = options.top_k.get(HEAD).copied().unwrap_or(0);
+        let relation_k = options.top_k.get(RELATION).copied().unwrap_or(0);
+
+        let probs = if options.confidences
+            || head_k > 0
+            || relation_k > 0
+            || options.head_scores == HeadScores::Probabilities
+        {
+            Some(BiaffineProbs::new(&biaffine_score_logits, relation_k)?)
+        } else {
+            None
+        };
+
+        // For dependency relations, we only care about the best-scoring relations.
+        // This changes the shape from [batch_size, seq_len - 1, seq_len, n_relations] to
+        // [batch_size, seq_len - 1, seq_len].
+        let best_relations: Array3<i32> = match &probs {
+            Some(probs) => probs.relations.index_axis(Axis(3), 0).to_owned(),
+            None => to_array(
+                &biaffine_score_logits
+                    .relation_score_logits
+                    .f_argmax(-1, false)?,
+            )?,
+        };
+
+        for (idx, sentence) in sentences.iter_mut().enumerate() {
+            let n_tokens = sentence.token_offsets.len();
+
+            // The decoder expects square matrices that include the root as
+            // a dependent. The root row is not used during decoding.
+            let mut sent_head_scores = Array2::<f32>::zeros((n_tokens + 1, n_tokens + 1));
+            sent_head_scores
+                .slice_mut(s![1.., ..])
+                .assign(&head_score_logits.slice(s![idx, ..n_tokens, ..n_tokens + 1]));
+
+            let mut sent_best_relations = Array2::<i32>::zeros((n_tokens + 1, n_tokens + 1));
+            sent_best_relations
+                .slice_mut(s![1.., ..])
+                .assign(&best_relations.slice(s![idx, ..n_tokens, ..n_tokens + 1]));
+
+            let kept = match policy {
+                AnnotationPolicy::Overwrite => {
+                    decoder.decoder.decode(
+                        sent_head_scores.view(),
+                        sent_best_relations.view(),
+                        &mut sentence.sentence,
+                    );
+                    vec![false; n_tokens]
+                }
+                AnnotationPolicy::KeepIfPresent => {
+                    // Restrict present relations to their heads, so that the
+                    // decoder finds a tree that contains them.
+                    fix_present_arcs(&sentence.sentence, sent_head_scores.view_mut());
+
+                    let original = sentence.sentence.clone();
+                    decoder.decoder.decode(
+                        sent_head_scores.view(),
+                        sent_best_relations.view(),
+                        &mut sentence.sentence,
+                    );
+                    keep_present_dependencies(&original, &mut sentence.sentence)
+                }
+                AnnotationPolicy::Skip => Vec::new(),
+            };
+
+            scores[idx].head_scores = match options.head_scores {
+                HeadScores::None => None,
+                HeadScores::Logits => Some(
+                    head_score_logits
+                        .slice(s![idx, ..n_tokens, ..n_tokens + 1])
+                        .to_owned(),
+                ),
+                HeadScores::Probabilities => probs.as_ref().map(|probs| {
+                    probs
+                        .heads
+                        .slice(s![idx, ..n_tokens, ..n_tokens + 1])
+                        .to_owned()
+                }),
+            };
+
+            // Skipped dependencies are not scored.
+            let probs = match &probs {
+                Some(probs) if policy != AnnotationPolicy::Skip => probs,
+                _ => continue,
+            };
+
+            let dep_graph = sentence.sentence.dep_graph();
+            for (dependent, (token_scores, kept)) in
+                (1..=n_tokens).zip(scores[idx].tokens.iter_mut().zip(kept))
+            {
+                let head = dep_graph
+                    .head(dependent)
+                    .expect("Decoder did not assign a head")
+                    .head();
+
+                let head_probs = probs.heads.slice(s![idx, dependent - 1, ..n_tokens + 1]);
+                let relations = probs.relations.slice(s![idx, dependent - 1, head, ..]);
+                let relation_probs = probs.relation_probs.slice(s![idx, dependent - 1, head, ..]);
+
+                if options.confidences && !kept {
+                    token_scores
+                        .confidences
+                        .insert(HEAD.to_string(), head_probs[head]);
+                    token_scores
+                        .confidences
+                        .insert(RELATION.to_string(), relation_probs[0]);
+                }
+
+                if head_k > 0 {
+                    // A token cannot be its own head.
+                    let mut heads = head_probs
+                        .iter()
+                        .enumerate()
+                        .filter(|&(head, _)| head != dependent)
+                        .collect::<Vec<_>>();
+                    heads.sort_by(|(_, prob1), (_, prob2)| {
+                        prob2.partial_cmp(prob1).unwrap_or(Ordering::Equal)
+                    });
+
+                    token_scores.top_k.insert(
+                        HEAD.to_string(),
+                        heads
+                            .into_iter()
+                            .take(head_k)
+                            .map(|(head, &probability)| LabelScore {
+                                label: head.to_string(),
+                                probability,
+                            })
+                            .collect(),
+                    );
+                }
+
+                if relation_k > 0 {
+                    token_scores.top_k.insert(
+                        RELATION.to_string(),
+                        relations
+                            .iter()
+                            .zip(relation_probs)
+                            .map(|(&relation, &probability)| LabelScore {
+                                label: decoder.relations[relation as usize].clone(),
+                                probability,
+                            })
+                            .collect(),
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode sequence labels.
+    fn decode_sequence_labels(
+        &self,
+        sentences: &mut [&mut SentenceWithPieces],
+        constraints: &[&SentenceConstraints],
+        encoder_logits: HashMap<String, Tensor>,
+        scores: &mut [SentenceScores],
+        options: &AnnotateOptions,
+    ) -> Result<(), SyntaxDotError> {
+        // For each encoder, we get the top-k labels with shape [batch_size, seq_len, k].
+        // Convert the tensors to ndarray tensors, since they are easier to work with
+        // in Rust.
+        let mut top_k_tensors = HashMap::new();
+        for (encoder_name, logits) in encoder_logits {
+            if options.policy(&encoder_name) == AnnotationPolicy::Skip {
+                continue;
+            }
+
+            let k = cmp::max(
+                TOP_K,
+                options.top_k.get(&encoder_name).copied().unwrap_or(0),
+            ) as i64;
+            // The first classes are reserved for padding and continuation.
+            let n_labels = logits.size().last().copied().unwrap_or(0) - N_RESERVED_LABELS as i64;
+
+            let probs = logits
+                .f_softmax(-1, Kind::Float)?
+                // Exclude the reserved classes.
+                .f_slice(-1, N_RESERVED_LABELS as i64, i64::MAX, 1)?;
+            let (top_k_probs, top_k_labels) =
+                probs.f_topk(cmp::min(k, n_labels), -1, true, true)?;
+
+            let top_k_labels: ArrayD<i32> = (&top_k_labels).try_into()?;
+            let top_k_probs: ArrayD<f32> = (&top_k_probs).try_into()?;
+
+            // Constrained tokens can use labels outside the top-k, so the
+            // full distribution is needed.
+            let probs: Option<Array3<f32>> = if constraints
+                .iter()
+                .any(|constraints| constraints.constrains_layer(&encoder_name))
+            {
+                Some(to_array(&probs)?)
+            } else {
+                None
+            };
+
+            top_k_tensors.insert(encoder_name, (top_k_labels, top_k_probs, probs));
+        }
+
+        // Extract tensors per sentence.
+        for (idx, ((sentence, sent_constraints), sent_scores)) in sentences
+            .iter_mut()
+            .zip(constraints)
+            .zip(scores)
+            .enumerate()
+        {
+            for encoder in self.encoders.iter() {
+                let policy = options.policy(encoder.name());
+                if policy == AnnotationPolicy::Skip {
+                    continue;
+                }
+
+                let (top_k_labels, top_k_probs, probs) = &top_k_tensors[encoder.name()];
+
+                // Get the sentence and within the sentence the sequence elements
+                // that represent tokens.
+                let sent_top_k_labels = top_k_labels
+                    .index_axis(Axis(0), idx)
+                    .slice(s![..sentence.token_offsets.len(), ..])
+                    .to_owned();
+                let sent_top_k_probs = top_k_probs
+                    .index_axis(Axis(0), idx)
+                    .slice(s![..sentence.token_offsets.len(), ..])
+                    .to_owned();
+
+                // Collect sentence top-k, fixing the label offsets.
+                let mut label_probs: Vec<Vec<EncodingProb<usize>>> = sent_top_k_labels
+                    .outer_iter()
+                    .zip(sent_top_k_probs.outer_iter())
+                    .map(|(token_top_k_labels, token_top_k_probs)| {
+                        token_top_k_labels
+                            .iter()
+                            .zip(token_top_k_probs)
+                            .map(|(label, prob)| {
+                                EncodingProb::new(*label as usize + N_RESERVED_LABELS, *prob)
+                            })
+                            .collect()
+                    })
+                    .collect();
+
+                if let Some(probs) = probs {
+                    self.constrain_labels(
+                        encoder.name(),
+                        sent_constraints,
+                        probs.index_axis(Axis(0), idx),
+                        &mut label_probs,
+                    );
+                }
+
+                if sent_scores.tokens.is_empty() {
+                    self.decode_labels(
+                        encoder,
+                        &truncate_labels(&label_probs, TOP_K),
+                        &mut sentence.sentence,
+                        policy,
+                    )?;
+                } else {
+                    self.decode_with_scores(
+                        encoder,
+                        &label_probs,
+                        &mut sentence.sentence,
+                        &mut sent_scores.tokens,
+                        options,
+                    )?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decode sequence labels and store the scores of the labels.
+    ///
+    /// Decoders do not necessarily choose the most probable label, e.g.
+    /// a dependency label can only be used when the head exists. So, the
+    /// value that was chosen is looked up among the values of the top-k
+    /// candidates. The candidates are decoded per token from the label
+    /// inventory of the encoder.
+    fn decode_with_scores(
+        &self,
+        encoder: &NamedEncoder,
+        label_probs: &[Vec<EncodingProb<usize>>],
+        sentence: &mut Sentence,
+        scores: &mut [TokenScores],
+        options: &AnnotateOptions,
+    ) -> Result<(), SyntaxDotError> {
+        let decode_labels = truncate_labels(label_probs, TOP_K);
+        let policy = options.policy(encoder.name());
+
+        let (encoder_type, encoder_labels) = match (
+            self.encoder_types.get(encoder.name()),
+            self.encoder_labels.get(encoder.name()),
+        ) {
+            (Some(encoder_type), Some(encoder_labels)) => (encoder_type, encoder_labels),
+            _ => {
+                self.decode_labels(encoder, &decode_labels, sentence, policy)?;
+                return Ok(());
+            }
+        };
+
+        // Candidates are decoded before the sentence is modified, since
+        // dependency candidates depend on the part-of-speech tags.
+        let mut candidate_values = encoder_labels.candidate_values(sentence, label_probs);
+
+        let kept = self.decode_labels(encoder, &decode_labels, sentence, policy)?;
+
+        let top_k = options.top_k.get(encoder.name()).copied().unwrap_or(0);
+        for (((value, token_candidates), token_scores), (token_label_probs, kept)) in
+            label_values(encoder_type, sentence)
+                .into_iter()
+                .zip(&mut candidate_values)
+                .zip(scores)
+                .zip(label_probs.iter().zip(kept))
+        {
+            // Lemma decoders always use the most probable edit tree, but
+            // can apply transformations that depend on the sentence.
+            let is_lemma = matches!(
+                encoder_type,
+                EncoderType::Lemma(_) | EncoderType::TdzLemma(_)
+            );
+            if is_lemma && !kept {
+                if let Some(candidate) = token_candidates.first_mut() {
+                    *candidate = value.clone();
+                }
+            }
+
+            let token_candidates = token_candidates.iter().zip(token_label_probs);
+
+            if options.confidences && !kept {
+                let confidence = token_candidates
+                    .clone()
+                    .take(TOP_K)
+                    .find(|(candidate, _)| **candidate == value)
+                    .map(|(_, label_prob)| label_prob.prob())
+                    .unwrap_or(0.);
+
+                token_scores
+                    .confidences
+                    .insert(encoder.name().to_string(), confidence);
+            }
+
+            if top_k > 0 {
+                // Candidates that cannot be applied to the token are skipped.
+                token_scores.top_k.insert(
+                    encoder.name().to_string(),
+                    token_candidates
+                        .take(top_k)
+                        .filter_map(|(candidate, label_prob)| {
+                            candidate.clone().map(|label| LabelScore {
+                                label,
+                                probability: label_prob.prob(),
+                            })
+                        })
+                        .collect(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restrict the candidate labels of tokens to their allowed labels.
+    ///
+    /// The candidates of a constrained token are replaced by its allowed
+    /// labels, from most to least probable. The number of candidates is
+    /// not increased.
+    fn constrain_labels(
+        &self,
+        layer: &str,
+        constraints: &SentenceConstraints,
+        probs: ArrayView2<f32>,
+        label_probs: &mut [Vec<EncodingProb<usize>>],
+    ) {
+        let labels = match self.sequence_labels.get(layer) {
+            Some(labels) => labels,
+            None => return,
+        };
+
+        for (token_idx, token_label_probs) in label_probs.iter_mut().enumerate() {
+            let allowed = match constraints.allowed_labels(token_idx, layer) {
+                Some(allowed) => allowed,
+                None => continue,
+            };
+
+            // Probabilities do not include the reserved classes. Label
+            // inventories are checked to start after them when loading.
+            let mut candidates = allowed
+                .iter()
+                .filter_map(|label| labels.get(label))
+                .map(|&label| {
+                    EncodingProb::new(label, probs[[token_idx, label - N_RESERVED_LABELS]])
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_by(|label1, label2| {
+                label2
+                    .prob()
+                    .partial_cmp(&label1.prob())
+                    .unwrap_or(Ordering::Equal)
+            });
+            candidates.truncate(token_label_probs.len());
+
+            *token_label_probs = candidates;
+        }
+    }
+
+    /// Decode sequence labels using the annotation policy of the encoder.
+    ///
+    /// Returns for every token whether its value was kept.
+    fn decode_labels<S>(
+        &self,
+        encoder: &NamedEncoder,
+        labels: &[S],
+        sentence: &mut Sentence,
+        policy: AnnotationPolicy,
+    ) -> Result<Vec<bool>, SyntaxDotError>
+    where
+        S: AsRef<[EncodingProb<usize>]>,
+    {
+        let original = match policy {
+            AnnotationPolicy::KeepIfPresent => Some(sentence.clone()),
+            _ => None,
+        };
+
+        encoder.encoder().decode(labels, sentence)?;
+
+        Ok(match (original, self.encoder_types.get(encoder.name())) {
+            (Some(original), Some(encoder_type)) => {
+                keep_present_values(encoder_type, &original, sentence)
+            }
+            _ => vec![false; sentence.len() - 1],
+        })
+    }
+}
+
+/// Probabilities of the biaffine parser.
+struct BiaffineProbs {
+    /// Head probabilities, shape `[batch_size, seq_len - 1, seq_len]`.
+    heads: Array3<f32>,
+
+    /// The most probable relations, shape `[batch_size, seq_len - 1, seq_len, k]`.
+    relations: Array4<i32>,
+
+    /// Probabilities of the most probable relations.
+    relation_probs: Array4<f32>,
+}
+
+impl BiaffineProbs {
+    /// Compute probabilities, keeping (at least) the best relation.
+    fn new(
+        biaffine_score_logits: &BiaffineScoreLogits,
+        relation_k: usize,
+    ) -> Result<Self, SyntaxDotError> {
+        let heads = biaffine_score_logits
+            .head_score_logits
+            .f_softmax(-1, Kind::Float)?;
+
+        let relation_probs = biaffine_score_logits
+            .relation_score_logits
+            .f_softmax(-1, Kind::Float)?;
+        let n_relations = relation_probs.size().last().copied().unwrap_or(0);
+        let (relation_probs, relations) = relation_probs.f_topk(
+            cmp::max(1, cmp::min(relation_k as i64, n_relations)),
+            -1,
+            true,
+            true,
+        )?;
+
+        Ok(BiaffineProbs {
+            heads: to_array(&heads)?,
+            relations: to_array(&relations)?,
+            relation_probs: to_array(&relation_probs)?,
+        })
+    }
+}
+
+/// Get the values that an encoder assigned to the tokens of a sentence.
+fn label_values(encoder_type: &EncoderType, sentence: &Sentence) -> Vec<Option<String>> {
+    match encoder_type {
+        EncoderType::Dependency { .. } => {
+            let dep_graph = sentence.dep_graph();
+            (1..sentence.len())
+                .map(|dependent| {
+                    dep_graph.head(dependent).map(|triple| {
+                        format!(
+                            "{}:{}",
+                            triple.head(),
+                            triple.relation().unwrap_or_default()
+                        )
+                    })
+                })
+                .collect()
+        }
+        EncoderType::Lemma(_) | EncoderType::TdzLemma(_) => sentence
+            .tokens()
+            .map(|token| token.lemma().map(ToOwned::to_owned))
+            .collect(),
+        EncoderType::Sequence(layer) => sentence.tokens().map(|token| token.value(layer)).collect(),
+    }
+}
+
+/// Keep the `k` most probable labels of every token.
+fn truncate_labels<T>(label_probs: &[Vec<T>], k: usize) -> Vec<&[T]> {
+    label_probs
+        .iter()
+        .map(|token_label_probs| &token_label_probs[..cmp::min(k, token_label_probs.len())])
+        .collect()
+}
+
+fn to_array<T, D>(tensor: &Tensor) -> Result<Array<T, D>, SyntaxDotError>
+where
+    for<'a> &'a Tensor: TryInto<ArrayD<T>, Error = ShapeError>,
+    D: Dimension,
+{
+    let array: ArrayD<T> = tensor.try_into()?;
+    Ok(array.into_dimensionality()?)
+}
@@ -0,0 +1,249 @@
+//! Sliding windows over sentences that exceed the maximum sequence length.
+
+use std::iter;
+use std::ops::Range;
+
+use ndarray::s;
+use syntaxdot_tokenizers::SentenceWithPieces;
+use udgraph::graph::Sentence;
+
+/// Sliding windows for sentences that exceed the maximum sequence length.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SlidingWindow {
+    /// The maximum number of pieces in a window.
+    pub max_len: usize,
+
+    /// The number of pieces that consecutive windows share.
+    pub overlap: usize,
+
+    /// Whether sentences end with a piece that does not belong to a
+    /// token, such as `</s>`. This piece is added to every window.
+    pub end_piece: bool,
+}
+
+/// A window of tokens.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TokenWindow {
+    /// The tokens that are encoded in the window.
+    pub tokens: Range<usize>,
+
+    /// The tokens that use the encoding of the window.
+    pub owned: Range<usize>,
+}
+
+/// Split a sentence into overlapping windows of tokens.
+///
+/// Every window contains the first piece of the sentence, the pieces of
+/// its tokens, and the end piece if the sentence has one. Together, they
+/// must not exceed the maximum length of `window`. A token that does not
+/// fit in a window on its own gets a window that is truncated. Consecutive windows share at most `overlap` pieces, the
+/// shared tokens are split between the windows in the middle.
+pub(crate) fn sliding_windows(
+    token_offsets: &[usize],
+    n_pieces: usize,
+    window: SlidingWindow,
+) -> Vec<TokenWindow> {
+    // The end piece is added to every window, so it does not belong to
+    // the last token.
+    let (n_pieces, max_len) = if window.end_piece {
+        (n_pieces - 1, window.max_len - 1)
+    } else {
+        (n_pieces, window.max_len)
+    };
+
+    let n_tokens = token_offsets.len();
+    let offset = |token: usize| token_offsets.get(token).copied().unwrap_or(n_pieces);
+
+    let mut windows: Vec<TokenWindow> = Vec::new();
+    let mut start = 0;
+    while start < n_tokens {
+        let mut end = start + 1;
+        while end < n_tokens && offset(end + 1) - offset(start) < max_len {
+            end += 1;
+        }
+
+        // The previous window owns the first half of the overlap.
+        let owned_start = match windows.last_mut() {
+            Some(previous) => {
+                let middle = (start + previous.tokens.end) / 2;
+                previous.owned.end = middle;
+                middle
+            }
+            None => 0,
+        };
+
+        windows.push(TokenWindow {
+            tokens: start..end,
+            owned: owned_start..end,
+        });
+
+        if end == n_tokens {
+            break;
+        }
+
+        // Start the next window at the first token from which at most
+        // `overlap` pieces are shared, while always making progress.
+        let mut next = end;
+        while next - 1 > start && offset(end) - offset(next - 1) <= window.overlap {
+            next -= 1;
+        }
+        start = next;
+    }
+
+    windows
+}
+
+/// Get the pieces of a window as a sentence without tokens.
+///
+/// The pieces are the first piece of the sentence, the pieces of the
+/// tokens of the window, and the end piece of the sentence if `window`
+/// uses end pieces. Windows of tokens that do not fit are truncated
+/// before the end piece.
+pub(crate) fn window_pieces(
+    sentence: &SentenceWithPieces,
+    token_window: &TokenWindow,
+    window: SlidingWindow,
+) -> SentenceWithPieces {
+    let (n_pieces, max_len, end_piece) = if window.end_piece {
+        let n_pieces = sentence.pieces.len() - 1;
+        (
+            n_pieces,
+            window.max_len - 1,
+            Some(sentence.pieces[n_pieces]),
+        )
+    } else {
+        (sentence.pieces.len(), window.max_len, None)
+    };
+    let offset = |token: usize| {
+        sentence
+            .token_offsets
+            .get(token)
+            .copied()
+            .unwrap_or(n_pieces)
+    };
+
+    let first = offset(token_window.tokens.start);
+    let last = offset(token_window.tokens.end);
+
+    let pieces = iter::once(sentence.pieces[0])
+        .chain(sentence.pieces.slice(s![first..last]).iter().copied())
+        .take(max_len)
+        .chain(end_piece)
+        .collect();
+
+    SentenceWithPieces {
+        pieces,
+        sentence: Sentence::new(),
+        token_offsets: sentence.token_offsets[token_window.tokens.clone()]
+            .iter()
+            .map(|&offset| offset - first + 1)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::array;
+    use syntaxdot_tokenizers::SentenceWithPieces;
+    use udgraph::graph::Sentence;
+
+    use super::{sliding_windows, window_pieces, SlidingWindow, TokenWindow};
+
+    #[test]
+    fn sliding_windows_split_overlap() {
+        let window = SlidingWindow {
+            max_len: 5,
+            overlap: 2,
+            end_piece: false,
+        };
+
+        // Without an end piece, the last token includes the last piece.
+        assert_eq!(
+            sliding_windows(&[1, 2, 4, 5, 7, 8], 10, window),
+            vec![
+                TokenWindow {
+                    tokens: 0..3,
+                    owned: 0..2
+                },
+                TokenWindow {
+                    tokens: 2..5,
+                    owned: 2..4
+                },
+                TokenWindow {
+                    tokens: 4..6,
+                    owned: 4..6
+                },
+            ]
+        );
+
+        // Tokens that do not fit in a window get their own window.
+        assert_eq!(
+            sliding_windows(&[1, 10], 12, window),
+            vec![
+                TokenWindow {
+                    tokens: 0..1,
+                    owned: 0..1
+                },
+                TokenWindow {
+                    tokens: 1..2,
+                    owned: 1..2
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn every_window_has_the_end_piece() {
+        let window = SlidingWindow {
+            max_len: 6,
+            overlap: 2,
+            end_piece: true,
+        };
+
+        // Pieces 1 to 8 belong to the tokens, 100 is the first and 200
+        // the end piece.
+        let sentence = SentenceWithPieces {
+            pieces: array![100, 1, 2, 3, 4, 5, 6, 7, 8, 200],
+            sentence: Sentence::new(),
+            token_offsets: vec![1, 2, 4, 5, 7, 8],
+        };
+
+        let windows = sliding_windows(&sentence.token_offsets, sentence.pieces.len(), window);
+        assert_eq!(
+            windows,
+            vec![
+                TokenWindow {
+                    tokens: 0..3,
+                    owned: 0..2
+                },
+                TokenWindow {
+                    tokens: 2..5,
+                    owned: 2..4
+                },
+                TokenWindow {
+                    tokens: 4..6,
+                    owned: 4..6
+                },
+            ]
+        );
+
+        let middle = window_pieces(&sentence, &windows[1], window);
+        assert_eq!(middle.pieces, array![100, 4, 5, 6, 7, 200]);
+        assert_eq!(middle.token_offsets, vec![1, 2, 4]);
+
+        let last = window_pieces(&sentence, &windows[2], window);
+        assert_eq!(last.pieces, array![100, 7, 8, 200]);
+        assert_eq!(last.token_offsets, vec![1, 2]);
+
+        // Windows that do not fit are truncated before the end piece.
+        let truncated = window_pieces(
+            &sentence,
+            &TokenWindow {
+                tokens: 0..6,
+                owned: 0..6,
+            },
+            window,
+        );
+        assert_eq!(truncated.pieces, array![100, 1, 2, 3, 4, 200]);
+    }
+}
@@ -0,0 +1,98 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::AnnotatorError;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A pool of worker threads that start jobs in the order of submission.
+pub struct WorkerPool {
+    max_queued: usize,
+    sender: Mutex<SyncSender<Job>>,
+}
+
+impl WorkerPool {
+    /// Start a pool with `n_workers` threads.
+    ///
+    /// The pool has at least one thread. At most `max_queued` jobs wait
+    /// for a worker, further submissions fail until a job is started.
+    pub fn new(n_workers: usize, max_queued: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(max_queued);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for _ in 0..n_workers.max(1) {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                // The lock is only held while waiting for a job.
+                let job = match receiver.lock().unwrap().recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+
+                // A panicking job must not stop the worker, since the
+                // pool would run out of workers.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            });
+        }
+
+        WorkerPool {
+            max_queued,
+            sender: Mutex::new(sender),
+        }
+    }
+
+    /// Run a job on one of the workers.
+    ///
+    /// Never blocks, `QueueFull` is returned when the queue of the pool
+    /// is full.
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) -> Result<(), AnnotatorError> {
+        match self.sender.lock().unwrap().try_send(Box::new(job)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(AnnotatorError::QueueFull(self.max_queued)),
+            Err(TrySendError::Disconnected(_)) => Err(AnnotatorError::WorkersStopped),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::WorkerPool;
+    use crate::AnnotatorError;
+
+    #[test]
+    fn workers_survive_panicking_jobs() {
+        let pool = WorkerPool::new(1, 2);
+        pool.execute(|| panic!("job panicked")).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(42).unwrap()).unwrap();
+        assert_eq!(receiver.recv().unwrap(), 42);
+    }
+
+    #[test]
+    fn full_queue_is_rejected() {
+        let pool = WorkerPool::new(1, 1);
+
+        // Keep the worker busy until the queue was checked.
+        let (started_sender, started_receiver) = mpsc::channel();
+        let (release_sender, release_receiver) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = release_receiver.recv();
+        })
+        .unwrap();
+        started_receiver.recv().unwrap();
+
+        pool.execute(|| ()).unwrap();
+        assert!(matches!(
+            pool.execute(|| ()),
+            Err(AnnotatorError::QueueFull(1))
+        ));
+
+        drop(release_sender);
+    }
+}